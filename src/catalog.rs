@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Local};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{RwLock, oneshot},
+    task,
+};
+
+use crate::disc::{Disc, DiscType};
+use crate::store;
+
+/// What came of a disc going in.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Outcome {
+    Ripping,
+    /// Ripped, and for DVDs and Blu-rays waiting to be encoded.
+    #[default]
+    Ripped,
+    Encoded,
+    /// Already ripped, and not ripped again.
+    Skipped,
+    Failed {
+        reason: String,
+    },
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Ripping => write!(f, "Ripping"),
+            Outcome::Ripped => write!(f, "Ripped"),
+            Outcome::Encoded => write!(f, "Encoded"),
+            Outcome::Skipped => write!(f, "Skipped"),
+            Outcome::Failed { reason } => write!(f, "Failed: {}", reason),
+        }
+    }
+}
+
+/// The HandBrake settings a rip was queued with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncodeSettings {
+    pub preset: String,
+    pub extension: String,
+    pub args: Vec<String>,
+}
+
+/// A disc torn has processed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    #[serde(default)]
+    pub id: String,
+    pub fingerprint: Option<String>,
+    pub title: String,
+    pub disc_type: DiscType,
+    pub device: String,
+    #[serde(alias = "ripped_at")]
+    pub started_at: DateTime<Local>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Local>>,
+    /// The titles ripped from a DVD or Blu-ray.
+    #[serde(default)]
+    pub titles: Vec<usize>,
+    /// Where the rip went: the raw folder of a DVD or Blu-ray, an album's
+    /// folder or a data disc's image.
+    pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub output_bytes: u64,
+    #[serde(default)]
+    pub encode: Option<EncodeSettings>,
+    #[serde(default)]
+    pub encodes: Vec<PathBuf>,
+    #[serde(default)]
+    pub encoded_bytes: u64,
+    #[serde(default)]
+    pub outcome: Outcome,
+}
+
+impl CatalogEntry {
+    pub fn new(disc: &Disc, disc_type: DiscType, fingerprint: Option<String>) -> CatalogEntry {
+        CatalogEntry {
+            id: format!("{}", uuid::Uuid::new_v4()),
+            fingerprint,
+            title: disc.title(),
+            disc_type,
+            device: disc.name.clone(),
+            started_at: Local::now(),
+            finished_at: None,
+            titles: Vec::new(),
+            output: None,
+            output_bytes: 0,
+            encode: None,
+            encodes: Vec::new(),
+            encoded_bytes: 0,
+            outcome: Outcome::Ripping,
+        }
+    }
+
+    pub fn finish(&mut self, outcome: Outcome) {
+        self.finished_at = Some(Local::now());
+        self.outcome = outcome;
+    }
+
+    /// Whether the disc made it off the drive, so putting it in again would
+    /// be a duplicate.
+    fn is_ripped(&self) -> bool {
+        matches!(self.outcome, Outcome::Ripped | Outcome::Encoded)
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        let paths = self.output.iter().chain(&self.encodes);
+
+        [
+            self.title.as_str(),
+            self.device.as_str(),
+            self.fingerprint.as_deref().unwrap_or_default(),
+        ]
+        .into_iter()
+        .map(str::to_owned)
+        .chain([self.disc_type.to_string(), self.outcome.to_string()])
+        .chain(paths.map(|path| path.display().to_string()))
+        .any(|field| field.to_lowercase().contains(query))
+    }
+}
+
+/// A disc that's been ripped before, waiting on the dashboard to be ripped
+/// again or skipped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DuplicatePrompt {
+    pub id: String,
+    pub device: String,
+    pub title: String,
+    pub previous: CatalogEntry,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Rerip,
+    Skip,
+}
+
+type Prompts = HashMap<String, (DuplicatePrompt, oneshot::Sender<Decision>)>;
+
+/// Every disc processed so far, kept under the state directory so it
+/// outlives the job history and re-inserted discs are recognised after a
+/// restart.
+#[derive(Clone)]
+pub struct Catalog {
+    path: PathBuf,
+    entries: Arc<RwLock<Vec<CatalogEntry>>>,
+    prompts: Arc<RwLock<Prompts>>,
+}
+
+impl Catalog {
+    pub async fn load(state_dir: &Path) -> Result<Catalog, Error> {
+        let path = state_dir.join("catalog.json");
+
+        let entries = if path.is_file() {
+            serde_json::from_slice(&fs::read(&path).await?)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Catalog {
+            path,
+            entries: Arc::new(RwLock::new(entries)),
+            prompts: Default::default(),
+        })
+    }
+
+    async fn save(&self, entries: &[CatalogEntry]) -> Result<(), Error> {
+        store::write_json_atomic(&self.path, &entries).await
+    }
+
+    /// The most recent successful rip of a disc, if it's been ripped before.
+    pub async fn find(&self, fingerprint: &str) -> Option<CatalogEntry> {
+        self.entries
+            .read()
+            .await
+            .iter()
+            .rev()
+            .find(|entry| entry.is_ripped() && entry.fingerprint.as_deref() == Some(fingerprint))
+            .cloned()
+    }
+
+    /// Adds an entry, or replaces the one with the same id.
+    pub async fn record(&self, entry: CatalogEntry) -> Result<(), Error> {
+        let mut entries = self.entries.write().await;
+
+        match entries.iter_mut().find(|existing| existing.id == entry.id) {
+            Some(existing) => *existing = entry,
+            None => entries.push(entry),
+        }
+
+        self.save(&entries).await
+    }
+
+    /// Notes how encoding the rip in `raw` went.
+    pub async fn encoded(
+        &self,
+        raw: &Path,
+        encodes: Vec<PathBuf>,
+        outcome: Outcome,
+    ) -> Result<(), Error> {
+        let mut encoded_bytes = 0;
+        for encode in &encodes {
+            encoded_bytes += disk_usage(encode).await;
+        }
+
+        let mut entries = self.entries.write().await;
+
+        let Some(entry) = entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.output.as_deref() == Some(raw))
+        else {
+            return Ok(());
+        };
+
+        entry.encodes = encodes;
+        entry.encoded_bytes = encoded_bytes;
+        entry.outcome = outcome;
+
+        self.save(&entries).await
+    }
+
+    /// Entries whose title, type, drive, fingerprint, outcome or paths
+    /// contain the query (ignoring case), newest first.
+    pub async fn search(&self, query: &str) -> Vec<CatalogEntry> {
+        let query = query.trim().to_lowercase();
+
+        self.entries
+            .read()
+            .await
+            .iter()
+            .rev()
+            .filter(|entry| query.is_empty() || entry.matches(&query))
+            .cloned()
+            .collect()
+    }
+
+    /// Waits for the dashboard to say what to do with a disc that's been
+    /// ripped before.
+    pub async fn ask(&self, device: &str, title: &str, previous: CatalogEntry) -> Decision {
+        let id = format!("{}", uuid::Uuid::new_v4());
+        let (sender, receiver) = oneshot::channel();
+
+        let prompt = DuplicatePrompt {
+            id: id.clone(),
+            device: device.to_owned(),
+            title: title.to_owned(),
+            previous,
+        };
+
+        self.prompts
+            .write()
+            .await
+            .insert(id.clone(), (prompt, sender));
+
+        let decision = receiver.await.unwrap_or(Decision::Skip);
+
+        self.prompts.write().await.remove(&id);
+
+        decision
+    }
+
+    pub async fn get_prompts(&self) -> Vec<DuplicatePrompt> {
+        let mut prompts: Vec<_> = self
+            .prompts
+            .read()
+            .await
+            .values()
+            .map(|(prompt, _)| prompt.clone())
+            .collect();
+
+        prompts.sort_by(|a, b| a.device.cmp(&b.device));
+        prompts
+    }
+
+    /// Answers a waiting prompt, false if there's no prompt with that id.
+    pub async fn decide(&self, id: &str, decision: Decision) -> bool {
+        match self.prompts.write().await.remove(id) {
+            Some((_, sender)) => {
+                let _ = sender.send(decision);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The size of a file, or of everything under a directory.
+pub async fn disk_usage(path: &Path) -> u64 {
+    fn walk(path: &Path) -> u64 {
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.is_dir() => std::fs::read_dir(path)
+                .map(|entries| entries.flatten().map(|entry| walk(&entry.path())).sum())
+                .unwrap_or(0),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        }
+    }
+
+    let path = path.to_owned();
+    task::spawn_blocking(move || walk(&path)).await.unwrap_or(0)
+}
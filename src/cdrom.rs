@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+// from linux/cdrom.h
+const CDROMREADTOCHDR: libc::c_ulong = 0x5305;
+const CDROMREADTOCENTRY: libc::c_ulong = 0x5306;
+const CDROM_LBA: u8 = 0x01;
+const CDROM_LEADOUT: u8 = 0xAA;
+const CDROM_DATA_TRACK: u8 = 0x04;
+const CDROM_CLOSETRAY: libc::c_ulong = 0x5319;
+const CDROM_LOCKDOOR: libc::c_ulong = 0x5329;
+const CDROM_DRIVE_STATUS: libc::c_ulong = 0x5326;
+const CDROM_DISC_STATUS: libc::c_ulong = 0x5327;
+/// Asks about the current slot, for drives that are changers.
+const CDSL_CURRENT: libc::c_ulong = libc::c_int::MAX as libc::c_ulong;
+const CDS_NO_INFO: libc::c_int = 0;
+const CDS_NO_DISC: libc::c_int = 1;
+const CDS_TRAY_OPEN: libc::c_int = 2;
+const CDS_DRIVE_NOT_READY: libc::c_int = 3;
+const CDS_DISC_OK: libc::c_int = 4;
+
+#[repr(C)]
+#[derive(Default)]
+struct TocHeader {
+    first_track: u8,
+    last_track: u8,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TocEntry {
+    track: u8,
+    /// `cdte_adr` in the low nibble, `cdte_ctrl` in the high nibble.
+    adr_ctrl: u8,
+    format: u8,
+    /// `cdte_addr`, a union of an MSF triple and an LBA; we always ask for LBA.
+    lba: libc::c_int,
+    datamode: u8,
+}
+
+/// The table of contents of a CD, with addresses in sectors.
+#[derive(Clone, Debug)]
+pub struct Toc {
+    pub tracks: Vec<TocTrack>,
+    pub leadout: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TocTrack {
+    pub number: u8,
+    pub start: u32,
+    pub audio: bool,
+}
+
+impl Toc {
+    pub fn audio_tracks(&self) -> impl Iterator<Item = &TocTrack> {
+        self.tracks.iter().filter(|t| t.audio)
+    }
+}
+
+/// What a drive's tray is up to.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DriveState {
+    Open,
+    Empty,
+    /// There's a disc in, but the drive is still spinning it up.
+    NotReady,
+    DiscOk,
+}
+
+impl fmt::Display for DriveState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriveState::Open => write!(f, "Tray open"),
+            DriveState::Empty => write!(f, "No disc"),
+            DriveState::NotReady => write!(f, "Spinning up"),
+            DriveState::DiscOk => write!(f, "Disc ready"),
+        }
+    }
+}
+
+fn open(device: &str) -> io::Result<File> {
+    // O_NONBLOCK lets us open the drive even when there's no disc in it
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(device)
+}
+
+pub fn read_toc(device: &str) -> io::Result<Toc> {
+    let file = open(device)?;
+    let fd = file.as_raw_fd();
+
+    let mut header = TocHeader::default();
+    if unsafe { libc::ioctl(fd, CDROMREADTOCHDR, &mut header) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let read_entry = |track: u8| -> io::Result<TocEntry> {
+        let mut entry = TocEntry {
+            track,
+            format: CDROM_LBA,
+            ..Default::default()
+        };
+        if unsafe { libc::ioctl(fd, CDROMREADTOCENTRY, &mut entry) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(entry)
+    };
+
+    let mut tracks = Vec::new();
+
+    for number in header.first_track..=header.last_track {
+        let entry = read_entry(number)?;
+        tracks.push(TocTrack {
+            number,
+            start: entry.lba.max(0) as u32,
+            audio: (entry.adr_ctrl >> 4) & CDROM_DATA_TRACK == 0,
+        });
+    }
+
+    let leadout = read_entry(CDROM_LEADOUT)?.lba.max(0) as u32;
+
+    Ok(Toc { tracks, leadout })
+}
+
+pub fn drive_state(device: &str) -> io::Result<DriveState> {
+    let file = open(device)?;
+    let fd = file.as_raw_fd();
+
+    let status = unsafe { libc::ioctl(fd, CDROM_DRIVE_STATUS, CDSL_CURRENT) };
+    if status < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match status {
+        CDS_TRAY_OPEN => Ok(DriveState::Open),
+        CDS_NO_DISC => Ok(DriveState::Empty),
+        CDS_DRIVE_NOT_READY => Ok(DriveState::NotReady),
+        CDS_DISC_OK => Ok(DriveState::DiscOk),
+        // the drive can't tell us about its tray, so ask about the disc instead
+        _ => match unsafe { libc::ioctl(fd, CDROM_DISC_STATUS) } {
+            status if status < 0 => Err(io::Error::last_os_error()),
+            // no info here means there are no tracks to read
+            CDS_NO_DISC | CDS_NO_INFO => Ok(DriveState::Empty),
+            _ => Ok(DriveState::DiscOk),
+        },
+    }
+}
+
+pub fn close_tray(device: &str) -> io::Result<()> {
+    let file = open(device)?;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), CDROM_CLOSETRAY) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Stops (or lets) the tray open, whether from the drive's button or an
+/// eject. The lock outlives the file handle, until it's unlocked again.
+pub fn lock_tray(device: &str, locked: bool) -> io::Result<()> {
+    let file = open(device)?;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), CDROM_LOCKDOOR, locked as libc::c_int) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Holds {
+    /// Locked through the web API.
+    manual: bool,
+    /// Locked for a rip in progress.
+    rip: bool,
+}
+
+/// Who's holding each drive's tray locked. The tray is only unlocked once
+/// neither the user nor a rip wants it locked, so finishing a rip doesn't
+/// undo a lock set by hand.
+#[derive(Clone, Debug, Default)]
+pub struct TrayLocks(Arc<Mutex<HashMap<String, Holds>>>);
+
+impl TrayLocks {
+    /// Locks or unlocks a tray by hand. Returns false if an unlock has to
+    /// wait for the rip in progress to finish.
+    pub fn set_manual(&self, device: &str, locked: bool) -> io::Result<bool> {
+        let mut holds = self.0.lock().unwrap();
+        let hold = holds.entry(device.to_owned()).or_default();
+
+        let apply = locked || !hold.rip;
+        if apply {
+            lock_tray(device, locked)?;
+        }
+
+        hold.manual = locked;
+        Ok(apply)
+    }
+
+    /// Locks a tray for the length of a rip.
+    pub fn hold(&self, device: &str) -> io::Result<TrayLock> {
+        let mut holds = self.0.lock().unwrap();
+
+        lock_tray(device, true)?;
+        holds.entry(device.to_owned()).or_default().rip = true;
+
+        Ok(TrayLock {
+            device: device.to_owned(),
+            locks: self.clone(),
+        })
+    }
+}
+
+/// Keeps a drive's tray locked for a rip until dropped.
+pub struct TrayLock {
+    device: String,
+    locks: TrayLocks,
+}
+
+impl Drop for TrayLock {
+    fn drop(&mut self) {
+        let mut holds = self.locks.0.lock().unwrap();
+        let hold = holds.entry(self.device.clone()).or_default();
+
+        hold.rip = false;
+
+        if !hold.manual
+            && let Err(e) = lock_tray(&self.device, false)
+        {
+            warn!("Unable to unlock the tray of {}: {}", self.device, e);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use failure::{Error, format_err};
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+
+/// Runs an external command, appending the command line and its output to
+/// a log.
+pub async fn run(command: &[String], log: &mut fs::File) -> Result<(), Error> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| format_err!("empty command in config"))?;
+
+    log.write_all(format!("$ {}\n", command.join(" ")).as_bytes())
+        .await?;
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format_err!("unable to run {}: {}", program, e))?;
+
+    log.write_all(&output.stdout).await?;
+    log.write_all(&output.stderr).await?;
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "error code {:?} from {}",
+            output.status.code(),
+            program
+        ));
+    }
+
+    Ok(())
+}
+
+/// Substitutes `{name}` placeholders in a configured command. Each argument
+/// is filled in one pass, so a value that itself contains a placeholder
+/// (say a track called "{output}") is left as it is. Unknown placeholders
+/// are kept.
+pub fn fill(template: &[String], vars: &[(&str, &str)]) -> Vec<String> {
+    template
+        .iter()
+        .map(|arg| {
+            let mut filled = String::new();
+            let mut rest = arg.as_str();
+
+            while let Some(start) = rest.find('{') {
+                let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                    break;
+                };
+
+                let name = &rest[start + 1..end];
+                match vars.iter().find(|(var, _)| *var == name) {
+                    Some((_, value)) => {
+                        filled.push_str(&rest[..start]);
+                        filled.push_str(value);
+                    }
+                    None => filled.push_str(&rest[..=end]),
+                }
+
+                rest = &rest[end + 1..];
+            }
+
+            filled.push_str(rest);
+            filled
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        assert_eq!(
+            fill(
+                &template(&["flac", "--tag=TITLE={title}", "-o", "{output}/{title}.flac"]),
+                &[("title", "Intro"), ("output", "/music/Album")]
+            ),
+            template(&["flac", "--tag=TITLE=Intro", "-o", "/music/Album/Intro.flac"])
+        );
+    }
+
+    #[test]
+    fn values_are_not_filled_again() {
+        assert_eq!(
+            fill(
+                &template(&["--tag=TITLE={title}", "{output}"]),
+                &[
+                    ("title", "{output} {device}"),
+                    ("output", "/music"),
+                    ("device", "/dev/sr0")
+                ]
+            ),
+            template(&["--tag=TITLE={output} {device}", "/music"])
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(
+            fill(
+                &template(&["{nope}", "{output", "a}b{output}"]),
+                &[("output", "/music")]
+            ),
+            template(&["{nope}", "{output", "a}b/music"])
+        );
+    }
+}
@@ -4,9 +4,46 @@ use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Options {
+    pub detection: Detection,
     #[serde(with = "humantime_serde")]
     pub sleep_time: Duration,
     pub devices: Vec<String>,
+    pub duplicates: DuplicatePolicy,
+}
+
+impl Options {
+    /// A configured drive, by its position in `devices` or its id.
+    pub fn drive(&self, id: &str) -> Option<&str> {
+        if let Ok(index) = id.parse::<usize>() {
+            return self.devices.get(index).map(String::as_str);
+        }
+
+        self.devices
+            .iter()
+            .map(String::as_str)
+            .find(|device| drive_id(device) == id)
+    }
+}
+
+/// A drive's id in the web API, the name of its device node (`sr0` for
+/// `/dev/sr0`).
+pub fn drive_id(device: &str) -> &str {
+    device.rsplit('/').next().unwrap_or(device)
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    Skip,
+    Rerip,
+    Ask,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Detection {
+    Udev,
+    Poll,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -14,26 +51,117 @@ pub struct Directory {
     pub logs: String,
     pub raw: String,
     pub output: String,
+    pub state: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct MakeMKV {
     pub enqueue_existing_jobs: bool,
     pub args: Vec<String>,
+    pub selection: Selection,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Selection {
+    pub strategy: SelectionStrategy,
+    #[serde(with = "humantime_serde")]
+    pub min_length: Duration,
+    #[serde(with = "humantime_serde")]
+    pub episode_min_length: Duration,
+    #[serde(with = "humantime_serde")]
+    pub episode_max_length: Duration,
+    pub titles: Vec<usize>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    MainFeature,
+    Episodes,
+    AllAbove,
+    Titles,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Handbrake {
     pub delete_on_complete: bool,
+    #[serde(default, with = "humantime_serde")]
+    pub retain_raw_for: Option<Duration>,
+    pub trash_dir: Option<String>,
+    pub inputs: Vec<String>,
+    pub template: String,
+    pub movie_template: String,
+    pub show_template: String,
+    pub episode_template: String,
+    pub workers: usize,
+    pub threads: Option<usize>,
+    pub verify: Verify,
     pub dvd: HandbrakeArgs,
     pub bluray: HandbrakeArgs,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Verify {
+    pub probe: Probe,
+    #[serde(with = "humantime_serde")]
+    pub duration_tolerance: Duration,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Probe {
+    Ffprobe,
+    Handbrake,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct HandbrakeArgs {
     pub extension: String,
     pub preset: String,
     pub args: Vec<String>,
+    pub template: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Music {
+    pub musicbrainz_url: String,
+    pub ripper: Vec<String>,
+    pub flac_args: Vec<String>,
+    pub lossy: Option<LossyFormat>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LossyFormat {
+    pub extension: String,
+    pub command: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Data {
+    pub strategy: ReadStrategy,
+    pub retries: u32,
+    pub extract: Option<Vec<String>>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadStrategy {
+    Direct,
+    Ddrescue,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Metadata {
+    pub provider: Provider,
+    pub base_url: String,
+    pub api_key: String,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    None,
+    Tmdb,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -42,6 +170,9 @@ pub struct Settings {
     pub directory: Directory,
     pub makemkv: MakeMKV,
     pub handbrake: Handbrake,
+    pub music: Music,
+    pub data: Data,
+    pub metadata: Metadata,
 }
 
 impl TryFrom<Config> for Settings {
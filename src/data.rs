@@ -0,0 +1,256 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    ops::Range,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use failure::Error;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use tokio::{fs, task};
+
+use crate::command::{fill, run};
+use crate::config::{Data, ReadStrategy};
+use crate::disc::Disc;
+use crate::makemkv::RipStatuses;
+use crate::naming::{sanitise, unique_path};
+
+const SECTOR_SIZE: u64 = 2048;
+/// Sectors read at a time while the disc is reading cleanly.
+const CHUNK_SECTORS: u64 = 32;
+
+/// Images a data disc to `output/<title>.iso`, alongside a SHA-256 sidecar
+/// and a report of any sectors that couldn't be read, and optionally
+/// extracts its files into `output/<title>/`.
+pub async fn archive(
+    config: &Data,
+    disc: &Disc,
+    output: &Path,
+    log_dir: &Path,
+    statuses: &RipStatuses,
+) -> Result<PathBuf, Error> {
+    fs::create_dir_all(output).await?;
+    fs::create_dir_all(log_dir).await?;
+
+    let iso = unique_path(
+        &output.join(format!("{}.iso", sanitise(&disc.title()))),
+        Path::exists,
+    );
+    let partial = iso.with_extension("iso.partial");
+    let name = iso
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut log = fs::File::create(log_dir.join(format!("{}.log", name))).await?;
+
+    set_operation(statuses, &disc.name, "Imaging disc").await;
+
+    info!("Imaging {} into {}", disc.name, iso.display());
+
+    let bad_sectors = match config.strategy {
+        ReadStrategy::Direct => {
+            let device = disc.name.clone();
+            let target = partial.clone();
+            let retries = config.retries;
+            let statuses = statuses.clone();
+
+            task::spawn_blocking(move || {
+                image_direct(&device, &target, retries, |progress| {
+                    if let Some(status) = statuses.blocking_write().get_mut(&device) {
+                        status.total_progress = progress;
+                    }
+                })
+            })
+            .await??
+        }
+        ReadStrategy::Ddrescue => {
+            let mapfile = iso.with_extension("map");
+
+            run(
+                &[
+                    "ddrescue".to_owned(),
+                    "-b".to_owned(),
+                    SECTOR_SIZE.to_string(),
+                    format!("-r{}", config.retries),
+                    disc.name.clone(),
+                    partial.to_string_lossy().into_owned(),
+                    mapfile.to_string_lossy().into_owned(),
+                ],
+                &mut log,
+            )
+            .await?;
+
+            let bad_sectors = parse_mapfile(&fs::read_to_string(&mapfile).await?);
+            fs::remove_file(&mapfile).await?;
+            bad_sectors
+        }
+    };
+
+    fs::rename(&partial, &iso).await?;
+
+    set_operation(statuses, &disc.name, "Checksumming image").await;
+
+    let path = iso.clone();
+    let checksum = task::spawn_blocking(move || sha256(&path)).await??;
+
+    fs::write(
+        iso.with_extension("iso.sha256"),
+        format!("{}  {}.iso\n", checksum, name),
+    )
+    .await?;
+
+    let sectors = fs::metadata(&iso).await?.len() / SECTOR_SIZE;
+
+    if !bad_sectors.is_empty() {
+        warn!(
+            "{} sectors of {} couldn't be read and were zero-filled",
+            bad_sectors.iter().map(|r| r.end - r.start).sum::<u64>(),
+            disc.name
+        );
+    }
+
+    fs::write(
+        output.join(format!("{}.errors.txt", name)),
+        error_report(&name, config, sectors, &bad_sectors),
+    )
+    .await?;
+
+    if let Some(extract) = &config.extract {
+        set_operation(statuses, &disc.name, "Extracting files").await;
+
+        let target = output.join(&name);
+        fs::create_dir_all(&target).await?;
+
+        let command = fill(
+            extract,
+            &[
+                ("input", &iso.to_string_lossy()),
+                ("output", &target.to_string_lossy()),
+            ],
+        );
+
+        run(&command, &mut log).await?;
+    }
+
+    Ok(iso)
+}
+
+async fn set_operation(statuses: &RipStatuses, device: &str, operation: &str) {
+    if let Some(status) = statuses.write().await.get_mut(device) {
+        status.operation = Some(operation.to_owned());
+    }
+}
+
+/// Copies the disc a chunk at a time, falling back to single sectors (with
+/// retries) around read errors so only the sectors that really are
+/// unreadable get zero-filled.
+fn image_direct(
+    device: &str,
+    target: &Path,
+    retries: u32,
+    on_progress: impl Fn(f32),
+) -> io::Result<Vec<Range<u64>>> {
+    let mut source = File::open(device)?;
+    let sectors = source.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+
+    let mut target = File::create(target)?;
+    let mut buffer = vec![0; (CHUNK_SECTORS * SECTOR_SIZE) as usize];
+    let mut bad_sectors: Vec<Range<u64>> = Vec::new();
+    let mut reported = 0;
+
+    let mut sector = 0;
+    while sector < sectors {
+        let count = CHUNK_SECTORS.min(sectors - sector);
+        let chunk = &mut buffer[..(count * SECTOR_SIZE) as usize];
+
+        if source.read_exact_at(chunk, sector * SECTOR_SIZE).is_err() {
+            for (offset, block) in chunk.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+                let bad = sector + offset as u64;
+
+                let readable =
+                    (0..=retries).any(|_| source.read_exact_at(block, bad * SECTOR_SIZE).is_ok());
+
+                if readable {
+                    continue;
+                }
+
+                block.fill(0);
+
+                match bad_sectors.last_mut() {
+                    Some(range) if range.end == bad => range.end += 1,
+                    _ => bad_sectors.push(bad..bad + 1),
+                }
+            }
+        }
+
+        target.write_all(chunk)?;
+        sector += count;
+
+        // only report whole percentages, the status lock isn't free
+        let percent = sector * 100 / sectors;
+        if percent != reported {
+            reported = percent;
+            on_progress(sector as f32 / sectors as f32);
+        }
+    }
+
+    target.sync_all()?;
+
+    Ok(bad_sectors)
+}
+
+/// Picks the unrecovered areas out of a ddrescue mapfile, as sector ranges.
+fn parse_mapfile(contents: &str) -> Vec<Range<u64>> {
+    let parse_hex = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        // the first line is ddrescue's current position, not a block
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let position = parse_hex(fields.next()?)?;
+            let size = parse_hex(fields.next()?)?;
+            let status = fields.next()?;
+
+            (status != "+").then(|| position / SECTOR_SIZE..(position + size).div_ceil(SECTOR_SIZE))
+        })
+        .collect()
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn error_report(name: &str, config: &Data, sectors: u64, bad_sectors: &[Range<u64>]) -> String {
+    let unreadable: u64 = bad_sectors.iter().map(|r| r.end - r.start).sum();
+
+    let mut report = format!(
+        "Image: {}.iso\nStrategy: {:?}, {} retries\nSectors: {}\nUnreadable sectors: {}\n",
+        name, config.strategy, config.retries, sectors, unreadable
+    );
+
+    if !bad_sectors.is_empty() {
+        report.push_str("\nZero-filled ranges:\n");
+
+        for range in bad_sectors {
+            report.push_str(&format!(
+                "{}-{} ({} sectors)\n",
+                range.start,
+                range.end - 1,
+                range.end - range.start
+            ));
+        }
+    }
+
+    report
+}
@@ -1,14 +1,19 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, fmt, path::Path, time::Duration};
 
+use chrono::{DateTime, Local};
 use heck::{ToShoutySnekCase, ToTitleCase};
 use serde::{Deserialize, Serialize};
 use tokio::{process::Command, time::sleep};
 
+use crate::metadata::MediaLookup;
+use crate::series::EpisodeMapping;
+
 #[derive(Clone, Debug)]
 pub struct Disc {
     pub name: String,
     pub r#type: Option<DiscType>,
     pub properties: HashMap<String, String>,
+    pub detected_at: DateTime<Local>,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
@@ -19,20 +24,70 @@ pub enum DiscType {
     Music,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+impl fmt::Display for DiscType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscType::BluRay => write!(f, "Blu-ray"),
+            DiscType::Data => write!(f, "Data"),
+            DiscType::Dvd => write!(f, "DVD"),
+            DiscType::Music => write!(f, "Music"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DiscMetadata {
     pub disc_type: DiscType,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub ripped_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub lookup: MediaLookup,
+    #[serde(default)]
+    pub series: Option<EpisodeMapping>,
+}
+
+#[derive(Debug)]
+pub enum DiscError {
+    /// The configured device path doesn't name a block device.
+    InvalidDevice(String),
+    /// udev couldn't be queried for the device.
+    Udev(String, std::io::Error),
+    /// The disc in the drive isn't a type we know how to handle.
+    UnknownType(String),
+    /// Ejecting the disc failed.
+    Eject(String, String),
+}
+
+impl fmt::Display for DiscError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscError::InvalidDevice(device) => write!(f, "invalid device path {}", device),
+            DiscError::Udev(device, e) => write!(f, "unable to query udev for {}: {}", device, e),
+            DiscError::UnknownType(device) => write!(f, "unknown disc type in {}", device),
+            DiscError::Eject(device, e) => write!(f, "unable to eject {}: {}", device, e),
+        }
+    }
 }
 
+impl std::error::Error for DiscError {}
+
 impl Disc {
-    pub fn new(device: &str) -> Disc {
-        let properties = get_device_proprties(device);
+    pub fn new(device: &str) -> Result<Disc, DiscError> {
+        let properties = get_device_proprties(device)?;
 
-        Disc {
+        Ok(Disc {
             name: device.to_owned(),
             r#type: get_device_type(&properties),
             properties,
-        }
+            detected_at: Local::now(),
+        })
+    }
+
+    pub fn disc_type(&self) -> Result<DiscType, DiscError> {
+        self.r#type
+            .ok_or_else(|| DiscError::UnknownType(self.name.clone()))
     }
 
     pub fn path_friendly_title(&self) -> String {
@@ -40,48 +95,62 @@ impl Disc {
     }
 
     pub fn title(&self) -> String {
-        if let Some(val) = self.properties.get("ID_FS_LABEL") {
-            if val != "iso9660" {
-                return val.to_title_case();
-            }
+        if let Some(val) = self.properties.get("ID_FS_LABEL")
+            && val != "iso9660"
+        {
+            return val.to_title_case();
         }
 
         if let Some(val) = self.properties.get("ID_FS_UUID") {
             return val.to_owned();
         }
 
-        unimplemented!()
+        // unlabelled discs (e.g. audio CDs) get named after the drive instead
+        let drive = Path::new(&self.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.name.clone());
+
+        format!("{} {}", drive, self.detected_at.format("%Y-%m-%d %H%M"))
     }
 }
 
-fn get_device_proprties(device: &str) -> HashMap<String, String> {
-    let sys_name = device.split('/').nth(2).unwrap();
+fn get_device_proprties(device: &str) -> Result<HashMap<String, String>, DiscError> {
+    // resolve symlinks such as /dev/cdrom to the real node, e.g. /dev/sr0
+    let path = std::fs::canonicalize(device).unwrap_or_else(|_| device.into());
+
+    let sys_name = path
+        .file_name()
+        .filter(|_| path.starts_with("/dev"))
+        .ok_or_else(|| DiscError::InvalidDevice(device.to_owned()))?;
 
-    let mut enumerator = udev::Enumerator::new().unwrap();
+    let udev_error = |e| DiscError::Udev(device.to_owned(), e);
 
-    enumerator.match_subsystem("block").unwrap();
+    let mut enumerator = udev::Enumerator::new().map_err(udev_error)?;
 
-    enumerator.match_sysname(sys_name).unwrap();
+    enumerator.match_subsystem("block").map_err(udev_error)?;
+
+    enumerator.match_sysname(sys_name).map_err(udev_error)?;
 
     let mut result = HashMap::new();
 
-    for device in enumerator.scan_devices().unwrap() {
+    for device in enumerator.scan_devices().map_err(udev_error)? {
         for p in device.properties() {
             result.insert(
-                p.name().to_str().unwrap().to_owned(),
-                p.value().to_str().unwrap().to_owned(),
+                p.name().to_string_lossy().into_owned(),
+                p.value().to_string_lossy().into_owned(),
             );
         }
     }
 
-    result
+    Ok(result)
 }
 
 fn get_device_type(properties: &HashMap<String, String>) -> Option<DiscType> {
-    if let Some(val) = properties.get("ID_FS_LABEL") {
-        if val == "iso9660" {
-            return Some(DiscType::Data);
-        }
+    if let Some(val) = properties.get("ID_FS_LABEL")
+        && val == "iso9660"
+    {
+        return Some(DiscType::Data);
     }
 
     if properties.get("ID_CDROM_MEDIA_BD").is_some() {
@@ -99,12 +168,21 @@ fn get_device_type(properties: &HashMap<String, String>) -> Option<DiscType> {
     None
 }
 
-pub async fn eject(disc: &Disc) {
-    Command::new("eject")
-        .arg(&disc.name)
+pub async fn eject(device: &str) -> Result<(), DiscError> {
+    let output = Command::new("eject")
+        .arg(device)
         .output()
         .await
-        .expect("failed to execute process");
+        .map_err(|e| DiscError::Eject(device.to_owned(), e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(DiscError::Eject(
+            device.to_owned(),
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
 
     sleep(Duration::from_secs(2)).await;
+
+    Ok(())
 }
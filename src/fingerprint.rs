@@ -0,0 +1,93 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind},
+    os::unix::fs::FileExt,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::cdrom;
+use crate::disc::DiscType;
+use crate::musicbrainz;
+use crate::udf::{self, SECTOR_SIZE, Udf};
+
+/// Where the ISO 9660 primary volume descriptor lives.
+const ISO_PRIMARY: u64 = 16;
+const UDF_PRIMARY_TAG: u16 = 1;
+const UDF_LOGICAL_TAG: u16 = 6;
+
+/// A stable ID for the disc in a drive, so it's recognised if it goes in
+/// again. Audio CDs use their MusicBrainz disc ID, DVDs a hash of their IFO
+/// files, Blu-rays their AACS disc ID and data discs a hash of the ISO 9660
+/// and UDF volume descriptors, which carry the volume's name, set identifier
+/// and creation time.
+pub fn fingerprint(device: &str, disc_type: DiscType) -> io::Result<String> {
+    if let DiscType::Music = disc_type {
+        let toc = cdrom::read_toc(device)?;
+        return Ok(format!("cd:{}", musicbrainz::disc_id(&toc)));
+    }
+
+    let file = File::open(device)?;
+
+    match disc_type {
+        DiscType::Dvd => Ok(format!("dvd:{}", dvd_id(&Udf::open(&file)?)?)),
+        DiscType::BluRay => Ok(format!("bluray:{}", aacs_id(&Udf::open(&file)?)?)),
+        _ => Ok(format!("data:{}", volume_id(&file, device)?)),
+    }
+}
+
+/// A hash of `VIDEO_TS.IFO` and every title set's `VTS_nn_0.IFO`, which
+/// between them describe the disc's menus, titles and chapters.
+fn dvd_id(udf: &Udf) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    hasher.update(udf.read_file(&["VIDEO_TS", "VIDEO_TS.IFO"])?);
+
+    let mut title_sets: Vec<String> = udf
+        .read_dir(&["VIDEO_TS"])?
+        .into_iter()
+        .map(|name| name.to_uppercase())
+        .filter(|name| name.starts_with("VTS_") && name.ends_with("_0.IFO"))
+        .collect();
+    title_sets.sort();
+
+    for title_set in title_sets {
+        hasher.update(udf.read_file(&["VIDEO_TS", &title_set])?);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The AACS disc ID: the SHA-1 of `AACS/Unit_Key_RO.inf`.
+fn aacs_id(udf: &Udf) -> io::Result<String> {
+    let unit_key = udf.read_file(&["AACS", "Unit_Key_RO.inf"])?;
+    Ok(format!("{:x}", Sha1::digest(unit_key)))
+}
+
+fn volume_id(file: &File, device: &str) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    let mut found = false;
+
+    let mut sector = vec![0; SECTOR_SIZE];
+    file.read_exact_at(&mut sector, ISO_PRIMARY * SECTOR_SIZE as u64)?;
+
+    if sector[0] == 1 && &sector[1..6] == b"CD001" {
+        hasher.update(&sector);
+        found = true;
+    }
+
+    for descriptor in udf::volume_descriptors(file)? {
+        if matches!(udf::tag(&descriptor), UDF_PRIMARY_TAG | UDF_LOGICAL_TAG) {
+            hasher.update(&descriptor);
+            found = true;
+        }
+    }
+
+    if !found {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no volume descriptors found on {}", device),
+        ));
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,80 +1,379 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use failure::{Error, format_err};
-use log::info;
+use futures::future::try_join_all;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use tokio::{
     fs,
+    io::{AsyncBufReadExt, BufReader},
     process::Command,
-    sync::RwLock,
-    sync::mpsc::{UnboundedSender, unbounded_channel},
+    sync::{Notify, RwLock},
     task::JoinHandle,
+    time,
 };
 
-use crate::config::Handbrake;
+use crate::catalog::{Catalog, Outcome};
+use crate::config::{Handbrake, HandbrakeArgs, Probe, Verify};
 use crate::disc::{DiscMetadata, DiscType};
+use crate::makemkv::DiscInfo;
+use crate::metadata::{MediaKind, MediaLookup};
+use crate::naming;
+use crate::queue::JobQueue;
+use crate::store::JobStore;
 
-#[derive(Debug, Clone)]
+/// Files torn writes next to a rip, left out of the ignored files reported
+/// on a job.
+const SIDECARS: &[&str] = &["meta.toml", "disc_info.json"];
+
+/// How often raw folders kept for `retain_raw_for` are checked.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum JobState {
+    Queued,
+    Running {
+        file_index: usize,
+        file_count: usize,
+        current_file: Option<String>,
+    },
+    Paused,
+    Succeeded,
+    Failed {
+        reason: String,
+        exit_code: Option<i32>,
+    },
+    Cancelled,
+}
+
+impl JobState {
+    /// The variant name, as used for the `kind` tag when serialised.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobState::Queued => "Queued",
+            JobState::Running { .. } => "Running",
+            JobState::Paused => "Paused",
+            JobState::Succeeded => "Succeeded",
+            JobState::Failed { .. } => "Failed",
+            JobState::Cancelled => "Cancelled",
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded | JobState::Failed { .. } | JobState::Cancelled
+        )
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobState::Queued => write!(f, "Queued"),
+            JobState::Running {
+                file_index,
+                file_count,
+                ..
+            } if *file_count > 0 => write!(f, "Encoding file {}/{}", file_index, file_count),
+            JobState::Running { .. } => write!(f, "Running"),
+            JobState::Paused => write!(f, "Paused"),
+            JobState::Succeeded => write!(f, "Completed"),
+            JobState::Failed { reason, .. } => write!(f, "Failed: {}", reason),
+            JobState::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum FileState {
+    Pending,
+    Encoding,
+    Done,
+    Failed { reason: String },
+}
+
+/// One of the ripped files a job encodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileStatus {
+    pub source: String,
+    pub output: Option<String>,
+    pub state: FileState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateTransition {
+    pub state: JobState,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobStatus {
     pub id: String,
     pub source: String,
     pub destination: String,
-    pub status: String,
+    pub state: JobState,
+    pub transitions: Vec<StateTransition>,
     pub started_at: String,
     pub progress: f32,
+    pub file_progress: f32,
+    pub fps: f32,
+    pub avg_fps: f32,
+    pub eta_seconds: Option<u64>,
+    pub worker: Option<usize>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub lookup: MediaLookup,
+    /// Problems that didn't fail the job, such as a raw folder that couldn't
+    /// be removed afterwards.
+    #[serde(default)]
+    pub log: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<FileStatus>,
+    /// Entries in the raw folder that didn't match `handbrake.inputs`.
+    #[serde(default)]
+    pub ignored: Vec<String>,
+    #[serde(default)]
+    pub raw_cleaned_up: bool,
 }
 
-#[derive(Clone)]
-pub struct HandbrakeProcess {
-    tx: UnboundedSender<Job>,
-    pub jobs: Arc<RwLock<HashMap<String, JobStatus>>>,
+impl JobStatus {
+    fn set_state(&mut self, state: JobState) {
+        self.transitions.push(StateTransition {
+            state: state.clone(),
+            at: Utc::now(),
+        });
+        self.state = state;
+    }
+
+    fn reset_progress(&mut self) {
+        self.progress = 0.0;
+        self.file_progress = 0.0;
+        self.fps = 0.0;
+        self.avg_fps = 0.0;
+        self.eta_seconds = None;
+        self.worker = None;
+    }
+}
+
+/// A `Progress: { ... }` block printed by `HandBrakeCLI --json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Progress {
+    state: String,
+    working: Option<Working>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Working {
+    progress: f32,
+    rate: f32,
+    rate_avg: f32,
+    #[serde(rename = "ETASeconds")]
+    eta_seconds: i64,
+}
+
+/// The `JSON Title Set: { ... }` block printed by `HandBrakeCLI --scan`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TitleSet {
+    title_list: Vec<ScannedTitle>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ScannedTitle {
+    duration: ScannedDuration,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ScannedDuration {
+    hours: u64,
+    minutes: u64,
+    seconds: u64,
+}
+
+/// Collects the `Name: { ... }` JSON blocks HandBrakeCLI prints to stdout,
+/// which can span many lines.
+#[derive(Default)]
+struct JsonBlocks {
+    name: Option<String>,
+    body: String,
+}
+
+impl JsonBlocks {
+    fn push(&mut self, line: &str) -> Option<(String, String)> {
+        if self.name.is_none() {
+            let (name, rest) = line.split_once(": {")?;
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == ' ') {
+                return None;
+            }
+            self.name = Some(name.to_owned());
+            self.body = format!("{{{}", rest);
+        } else {
+            self.body.push('\n');
+            self.body.push_str(line);
+        }
+
+        let depth =
+            self.body.matches('{').count() as isize - self.body.matches('}').count() as isize;
+
+        if depth > 0 {
+            return None;
+        }
+
+        Some((self.name.take()?, std::mem::take(&mut self.body)))
+    }
+}
+
+#[derive(Debug)]
+pub enum JobControlError {
+    NotFound(String),
+    InvalidState {
+        id: String,
+        action: &'static str,
+        state: JobState,
+    },
+}
+
+impl fmt::Display for JobControlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobControlError::NotFound(id) => write!(f, "job {} not found", id),
+            JobControlError::InvalidState { id, action, state } => {
+                write!(f, "job {} can't be {} while {}", id, action, state)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JobControlError {}
+
+/// A HandBrakeCLI exit with a non-zero status.
+#[derive(Debug)]
+struct HandbrakeExit(Option<i32>);
+
+impl fmt::Display for HandbrakeExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error code {:?} from handbrake, stopping process",
+            self.0
+        )
+    }
 }
 
+impl std::error::Error for HandbrakeExit {}
+
+/// Why a finished encode wasn't moved into the output directory.
 #[derive(Debug)]
-struct Job {
-    id: String,
-    src: PathBuf,
-    dest: PathBuf,
+enum VerifyError {
+    Empty,
+    Unreadable(Error),
+    Duration { source: Duration, output: Duration },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Empty => write!(f, "encode is empty"),
+            VerifyError::Unreadable(e) => write!(f, "encode can't be read: {}", e),
+            VerifyError::Duration { source, output } => write!(
+                f,
+                "encode runs for {}s but the source runs for {}s",
+                output.as_secs(),
+                source.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// The HandBrakeCLI process currently encoding a job, so it can be signalled.
+#[derive(Debug, Default)]
+struct RunningJob {
+    pid: Option<u32>,
+    cancelled: bool,
+    /// The state to go back to when a paused job is resumed.
+    paused_from: Option<JobState>,
+}
+
+#[derive(Clone)]
+pub struct HandbrakeProcess {
+    queue: Arc<JobQueue>,
+    running: Arc<StdMutex<HashMap<String, RunningJob>>>,
+    store: JobStore,
+    catalog: Catalog,
+    /// Wakes the cleanup task when a job finishes.
+    cleanup: Arc<Notify>,
+    pub jobs: Arc<RwLock<HashMap<String, JobStatus>>>,
 }
 
 impl HandbrakeProcess {
-    pub fn new(config: Handbrake) -> (HandbrakeProcess, JoinHandle<Result<(), Error>>) {
-        let (tx, mut rx) = unbounded_channel();
-        let jobs = Arc::new(RwLock::new(HashMap::<String, JobStatus>::new()));
-        let jobs_clone = jobs.clone();
+    pub async fn new(
+        config: Handbrake,
+        store: JobStore,
+        catalog: Catalog,
+    ) -> Result<(HandbrakeProcess, JoinHandle<Result<(), Error>>), Error> {
+        let queue = Arc::new(JobQueue::default());
+        let jobs = Arc::new(RwLock::new(store.load().await?));
 
-        let handle = tokio::spawn(async move {
-            while let Some(job) = rx.recv().await {
-                let job: Job = job;
-
-                // Update job status to "Processing"
-                {
-                    let mut jobs_map = jobs_clone.write().await;
-                    if let Some(job_status) = jobs_map.get_mut(&job.id) {
-                        job_status.status = "Processing".to_string();
-                        job_status.progress = 0.0;
-                    }
-                }
+        // Requeue anything that was waiting or running when we last stopped
+        {
+            let mut jobs_map = jobs.write().await;
+            let mut pending: Vec<&mut JobStatus> = jobs_map
+                .values_mut()
+                .filter(|job| !job.state.is_finished())
+                .collect();
 
-                match handbrake(&config, &job.src, &job.dest, &job.id, &jobs_clone).await {
-                    Ok(_) => {
-                        // Mark job as completed
-                        let mut jobs_map = jobs_clone.write().await;
-                        if let Some(job_status) = jobs_map.get_mut(&job.id) {
-                            job_status.status = "Completed".to_string();
-                            job_status.progress = 1.0;
-                        }
-                    }
-                    Err(e) => {
-                        // Mark job as failed
-                        let mut jobs_map = jobs_clone.write().await;
-                        if let Some(job_status) = jobs_map.get_mut(&job.id) {
-                            job_status.status = format!("Failed: {}", e);
-                            job_status.progress = 0.0;
-                        }
-                    }
+            pending.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+
+            for job_status in pending {
+                if job_status.state != JobState::Queued {
+                    warn!("requeuing interrupted job {}", job_status.id);
+                    job_status.set_state(JobState::Queued);
                 }
+
+                job_status.reset_progress();
+
+                queue.push(job_status.id.clone(), job_status.priority);
+            }
+
+            store.save(&jobs_map).await?;
+        }
+
+        let process = HandbrakeProcess {
+            queue,
+            running: Default::default(),
+            store,
+            catalog,
+            cleanup: Default::default(),
+            jobs,
+        };
+
+        let mut workers: Vec<_> = (0..config.workers.max(1))
+            .map(|worker| tokio::spawn(run_worker(worker, config.clone(), process.clone())))
+            .collect();
+
+        if config.delete_on_complete {
+            workers.push(tokio::spawn(run_cleanup(config.clone(), process.clone())));
+        }
+
+        let handle = tokio::spawn(async move {
+            for res in try_join_all(workers).await? {
+                res?;
             }
 
             info!("exiting handbrake process");
@@ -82,115 +381,896 @@ impl HandbrakeProcess {
             Ok(())
         });
 
-        (HandbrakeProcess { tx, jobs }, handle)
+        Ok((process, handle))
     }
 
     pub async fn queue(&self, src: PathBuf, dest: PathBuf) -> Result<(), Error> {
         let job_id = format!("{}", uuid::Uuid::new_v4());
-        let job_status = JobStatus {
+        let lookup = match fs::read(src.join("meta.toml")).await {
+            Ok(meta) => toml::from_slice::<DiscMetadata>(&meta)?.lookup,
+            Err(_) => MediaLookup::Skipped,
+        };
+        let mut job_status = JobStatus {
             id: job_id.clone(),
             source: src.display().to_string(),
             destination: dest.display().to_string(),
-            status: "Queued".to_string(),
+            state: JobState::Queued,
+            transitions: Vec::new(),
             started_at: chrono::Utc::now()
                 .format("%Y-%m-%d %H:%M:%S UTC")
                 .to_string(),
             progress: 0.0,
+            file_progress: 0.0,
+            fps: 0.0,
+            avg_fps: 0.0,
+            eta_seconds: None,
+            worker: None,
+            priority: 0,
+            lookup,
+            log: Vec::new(),
+            files: Vec::new(),
+            ignored: Vec::new(),
+            raw_cleaned_up: false,
         };
+        job_status.set_state(JobState::Queued);
 
         // Add job to tracking
         {
             let mut jobs_map = self.jobs.write().await;
             jobs_map.insert(job_id.clone(), job_status);
+            self.store.save(&jobs_map).await?;
         }
 
-        self.tx.send(Job {
-            id: job_id,
-            src,
-            dest,
-        })?;
+        self.queue.push(job_id, 0);
+
+        Ok(())
+    }
+
+    /// Stops a job. Queued jobs are dropped from the queue, running jobs have
+    /// their HandBrakeCLI process killed and the partial output removed.
+    pub async fn cancel(&self, id: &str) -> Result<(), JobControlError> {
+        let mut jobs_map = self.jobs.write().await;
+        let job_status = get_job(&mut jobs_map, id)?;
+
+        if job_status.state == JobState::Queued {
+            self.queue.remove(id);
+            job_status.set_state(JobState::Cancelled);
+        } else if !job_status.state.is_finished() {
+            let mut running = self.running.lock().unwrap();
+            let running_job = running.entry(id.to_owned()).or_default();
+            running_job.cancelled = true;
+            if let Some(pid) = running_job.pid {
+                // a stopped process has to be woken up before it can die
+                signal(pid, libc::SIGCONT);
+                signal(pid, libc::SIGKILL);
+            }
+        } else {
+            return Err(invalid_state(job_status, "cancelled"));
+        }
+
+        save_jobs(&self.store, &jobs_map).await;
+
+        Ok(())
+    }
+
+    pub async fn pause(&self, id: &str) -> Result<(), JobControlError> {
+        let mut jobs_map = self.jobs.write().await;
+        let job_status = get_job(&mut jobs_map, id)?;
+
+        let pid = self.running_pid(id);
+
+        match pid {
+            Some(pid) if matches!(job_status.state, JobState::Running { .. }) => {
+                signal(pid, libc::SIGSTOP);
+                if let Some(running_job) = self.running.lock().unwrap().get_mut(id) {
+                    running_job.paused_from = Some(job_status.state.clone());
+                }
+                job_status.set_state(JobState::Paused);
+            }
+            _ => return Err(invalid_state(job_status, "paused")),
+        }
+
+        save_jobs(&self.store, &jobs_map).await;
+
+        Ok(())
+    }
+
+    pub async fn resume(&self, id: &str) -> Result<(), JobControlError> {
+        let mut jobs_map = self.jobs.write().await;
+        let job_status = get_job(&mut jobs_map, id)?;
+
+        match self.running_pid(id) {
+            Some(pid) if job_status.state == JobState::Paused => {
+                signal(pid, libc::SIGCONT);
+                let state = self
+                    .running
+                    .lock()
+                    .unwrap()
+                    .get_mut(id)
+                    .and_then(|r| r.paused_from.take())
+                    .unwrap_or(JobState::Running {
+                        file_index: 0,
+                        file_count: 0,
+                        current_file: None,
+                    });
+                job_status.set_state(state);
+            }
+            _ => return Err(invalid_state(job_status, "resumed")),
+        }
+
+        save_jobs(&self.store, &jobs_map).await;
+
+        Ok(())
+    }
+
+    /// Puts a failed or cancelled job back on the queue. Files that were
+    /// already encoded aren't encoded again.
+    pub async fn retry(&self, id: &str) -> Result<(), JobControlError> {
+        let mut jobs_map = self.jobs.write().await;
+        let job_status = get_job(&mut jobs_map, id)?;
+
+        if !matches!(
+            job_status.state,
+            JobState::Cancelled | JobState::Failed { .. }
+        ) {
+            return Err(invalid_state(job_status, "retried"));
+        }
+
+        job_status.set_state(JobState::Queued);
+        job_status.reset_progress();
+        self.queue.push(id.to_owned(), job_status.priority);
+
+        save_jobs(&self.store, &jobs_map).await;
 
         Ok(())
     }
 
+    /// Higher priority jobs are picked up first.
+    pub async fn set_priority(&self, id: &str, priority: i32) -> Result<(), JobControlError> {
+        let mut jobs_map = self.jobs.write().await;
+        let job_status = get_job(&mut jobs_map, id)?;
+
+        job_status.priority = priority;
+        self.queue.reprioritise(id, priority);
+
+        save_jobs(&self.store, &jobs_map).await;
+
+        Ok(())
+    }
+
+    fn running_pid(&self, id: &str) -> Option<u32> {
+        self.running.lock().unwrap().get(id).and_then(|r| r.pid)
+    }
+
+    fn set_running_pid(&self, id: &str, pid: Option<u32>) {
+        if let Some(running_job) = self.running.lock().unwrap().get_mut(id) {
+            running_job.pid = pid;
+        }
+    }
+
+    fn is_cancelled(&self, id: &str) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .get(id)
+            .is_some_and(|r| r.cancelled)
+    }
+
+    /// Queues a raw folder found on startup. A folder that already has a job
+    /// isn't queued again, but if its latest job failed that job is retried,
+    /// keeping track of the files it had already encoded.
+    pub async fn enqueue_existing(&self, src: PathBuf, dest: PathBuf) -> Result<(), Error> {
+        let failed = {
+            let source = src.display().to_string();
+            let jobs_map = self.jobs.read().await;
+            let latest = jobs_map
+                .values()
+                .filter(|job| job.source == source)
+                .max_by(|a, b| a.started_at.cmp(&b.started_at));
+
+            match latest {
+                Some(job) if matches!(job.state, JobState::Failed { .. }) => Some(job.id.clone()),
+                Some(_) => return Ok(()),
+                None => None,
+            }
+        };
+
+        match failed {
+            Some(id) => {
+                info!("retrying failed job {} for {}", id, src.display());
+                self.retry(&id).await?;
+                Ok(())
+            }
+            None => self.queue(src, dest).await,
+        }
+    }
+
     pub async fn get_active_jobs(&self) -> Vec<JobStatus> {
         let jobs_map = self.jobs.read().await;
-        jobs_map
+        let mut active: Vec<JobStatus> = jobs_map
             .values()
-            .filter(|job| job.status != "Completed")
+            .filter(|job| !job.state.is_finished())
             .cloned()
-            .collect()
+            .collect();
+        active.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| a.started_at.cmp(&b.started_at))
+        });
+        active
+    }
+
+    /// Every job known to the store, most recent first.
+    pub async fn get_all_jobs(&self) -> Vec<JobStatus> {
+        let jobs_map = self.jobs.read().await;
+        let mut jobs: Vec<JobStatus> = jobs_map.values().cloned().collect();
+        jobs.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        jobs
+    }
+
+    /// Completed, failed and cancelled jobs, most recent first.
+    pub async fn get_job_history(&self) -> Vec<JobStatus> {
+        let jobs_map = self.jobs.read().await;
+        let mut history: Vec<JobStatus> = jobs_map
+            .values()
+            .filter(|job| job.state.is_finished())
+            .cloned()
+            .collect();
+        history.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        history
     }
 
     pub async fn get_queue_size(&self) -> usize {
         let jobs_map = self.jobs.read().await;
         jobs_map
             .values()
-            .filter(|job| job.status == "Queued")
+            .filter(|job| job.state == JobState::Queued)
             .count()
     }
 }
 
+fn get_job<'a>(
+    jobs: &'a mut HashMap<String, JobStatus>,
+    id: &str,
+) -> Result<&'a mut JobStatus, JobControlError> {
+    jobs.get_mut(id)
+        .ok_or_else(|| JobControlError::NotFound(id.to_owned()))
+}
+
+fn invalid_state(job_status: &JobStatus, action: &'static str) -> JobControlError {
+    JobControlError::InvalidState {
+        id: job_status.id.clone(),
+        action,
+        state: job_status.state.clone(),
+    }
+}
+
+fn signal(pid: u32, signal: libc::c_int) {
+    // pids handed out by the kernel always fit in a pid_t
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        warn!(
+            "unable to signal HandBrakeCLI process {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Takes the highest priority job off the shared queue, one at a time.
+async fn run_worker(
+    worker: usize,
+    config: Handbrake,
+    process: HandbrakeProcess,
+) -> Result<(), Error> {
+    loop {
+        let id = process.queue.pop().await;
+
+        // Update job status to "Processing"
+        let (src, dest) = {
+            let mut jobs_map = process.jobs.write().await;
+            let Some(job_status) = jobs_map.get_mut(&id) else {
+                continue;
+            };
+
+            if job_status.state != JobState::Queued {
+                continue;
+            }
+
+            job_status.set_state(JobState::Running {
+                file_index: 0,
+                file_count: 0,
+                current_file: None,
+            });
+            job_status.progress = 0.0;
+            job_status.worker = Some(worker);
+
+            let paths = (
+                PathBuf::from(&job_status.source),
+                PathBuf::from(&job_status.destination),
+            );
+            save_jobs(&process.store, &jobs_map).await;
+            paths
+        };
+
+        info!("worker {} starting job {}", worker, id);
+
+        // a cancel that came in since the job was dequeued has already
+        // added an entry, which must be kept
+        process
+            .running
+            .lock()
+            .unwrap()
+            .entry(id.clone())
+            .or_default();
+
+        let result = handbrake(&config, &src, &dest, &id, &process).await;
+
+        // cancel() takes the jobs lock first, so holding it here means a
+        // cancel either counts or sees the job finished
+        let mut jobs_map = process.jobs.write().await;
+
+        let cancelled = process.is_cancelled(&id);
+        process.running.lock().unwrap().remove(&id);
+
+        let mut outcome = None;
+        if let Some(job_status) = jobs_map.get_mut(&id) {
+            outcome = match &result {
+                _ if cancelled => None,
+                Ok(_) => Some(Outcome::Encoded),
+                Err(e) => Some(Outcome::Failed {
+                    reason: format!("encoding failed: {}", e),
+                }),
+            };
+
+            match result {
+                _ if cancelled => {
+                    info!("job {} cancelled", id);
+                    job_status.set_state(JobState::Cancelled);
+                    job_status.progress = 0.0;
+                }
+                // Mark job as completed
+                Ok(_) => {
+                    info!("job {} completed", id);
+                    job_status.set_state(JobState::Succeeded);
+                    job_status.progress = 1.0;
+                }
+                // Mark job as failed
+                Err(e) => {
+                    let exit_code = e.downcast_ref::<HandbrakeExit>().and_then(|e| e.0);
+                    job_status.set_state(JobState::Failed {
+                        reason: e.to_string(),
+                        exit_code,
+                    });
+                    job_status.progress = 0.0;
+                }
+            }
+        }
+        let encodes = jobs_map
+            .get(&id)
+            .map(|job_status| {
+                job_status
+                    .files
+                    .iter()
+                    .filter(|file| file.state == FileState::Done)
+                    .filter_map(|file| file.output.as_ref().map(PathBuf::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        save_jobs(&process.store, &jobs_map).await;
+        drop(jobs_map);
+
+        if let Some(outcome) = outcome
+            && let Err(e) = process.catalog.encoded(&src, encodes, outcome).await
+        {
+            warn!("unable to update the catalog for {}: {}", src.display(), e);
+        }
+
+        if config.delete_on_complete {
+            process.cleanup.notify_one();
+        }
+    }
+}
+
+/// Clears out raw folders once they're due, right after a job finishes and
+/// then periodically for those kept for `retain_raw_for`. This is the only
+/// caller of `clean_up_raw`, so two cleanups never race for the same folder.
+async fn run_cleanup(config: Handbrake, process: HandbrakeProcess) -> Result<(), Error> {
+    loop {
+        clean_up_raw(&config, &process).await;
+
+        tokio::select! {
+            _ = time::sleep(CLEANUP_INTERVAL) => {}
+            _ = process.cleanup.notified() => {}
+        }
+    }
+}
+
+/// Removes (or moves to `trash_dir`) the raw folders of jobs that succeeded
+/// at least `retain_raw_for` ago. Every file in them has a verified encode by
+/// then, so a folder that can't be removed is noted on the job rather than
+/// failing it.
+async fn clean_up_raw(config: &Handbrake, process: &HandbrakeProcess) {
+    let retain = config.retain_raw_for.unwrap_or_default();
+
+    let due: Vec<(String, PathBuf)> = process
+        .jobs
+        .read()
+        .await
+        .values()
+        .filter(|job| job.state == JobState::Succeeded && !job.raw_cleaned_up)
+        .filter(|job| {
+            job.transitions
+                .last()
+                .and_then(|transition| (Utc::now() - transition.at).to_std().ok())
+                .is_some_and(|age| age >= retain)
+        })
+        .map(|job| (job.id.clone(), PathBuf::from(&job.source)))
+        .collect();
+
+    if due.is_empty() {
+        return;
+    }
+
+    let mut results = Vec::new();
+
+    for (id, src) in due {
+        let result = if src.is_dir() {
+            remove_raw(config, &src).await
+        } else {
+            Ok(())
+        };
+
+        results.push((id, src, result));
+    }
+
+    let mut jobs_map = process.jobs.write().await;
+
+    for (id, src, result) in results {
+        let Some(job_status) = jobs_map.get_mut(&id) else {
+            continue;
+        };
+
+        job_status.raw_cleaned_up = true;
+
+        if let Err(e) = result {
+            warn!("unable to clean up {}: {}", src.display(), e);
+            job_status
+                .log
+                .push(format!("unable to clean up {}: {}", src.display(), e));
+        }
+    }
+
+    save_jobs(&process.store, &jobs_map).await;
+}
+
+async fn remove_raw(config: &Handbrake, src: &Path) -> Result<(), Error> {
+    match &config.trash_dir {
+        Some(trash_dir) => {
+            let trash_dir = Path::new(trash_dir);
+            fs::create_dir_all(trash_dir).await?;
+
+            let name = src
+                .file_name()
+                .ok_or_else(|| format_err!("{} has no folder name", src.display()))?;
+            let target = naming::unique_path(&trash_dir.join(name), Path::exists);
+
+            info!("moving {} to {}", src.display(), target.display());
+            fs::rename(src, target).await?;
+        }
+        None => {
+            info!("removing {}", src.display());
+            fs::remove_dir_all(src).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn save_jobs(store: &JobStore, jobs: &HashMap<String, JobStatus>) {
+    if let Err(e) = store.save(jobs).await {
+        warn!("unable to save job store: {}", e);
+    }
+}
+
+/// Fills in the template for a ripped file, giving its path relative to the
+/// output directory. Episodes of a confirmed series mapping use
+/// `episode_template`, identified movies and shows `movie_template` and
+/// `show_template`, and anything else the disc type's template or the
+/// default one.
+fn output_path(
+    config: &Handbrake,
+    args: &HandbrakeArgs,
+    src: &Path,
+    path: &Path,
+    meta: &DiscMetadata,
+    disc_info: Option<&DiscInfo>,
+) -> PathBuf {
+    let name = |path: Option<&std::ffi::OsStr>| {
+        path.map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    let file_name = name(path.file_name());
+    let rip_folder = name(src.file_name());
+    let title = disc_info.and_then(|info| {
+        info.titles
+            .iter()
+            .find(|t| t.output_file_name.as_deref() == Some(file_name.as_str()))
+    });
+
+    let mut vars = vec![
+        (
+            "disc_title",
+            meta.title.clone().unwrap_or_else(|| rip_folder.clone()),
+        ),
+        ("disc_type", meta.disc_type.to_string()),
+        ("rip_folder", rip_folder),
+        ("file", name(path.file_stem())),
+        (
+            "title_index",
+            title.map(|t| format!("{:02}", t.id)).unwrap_or_default(),
+        ),
+        (
+            "duration",
+            title
+                .map(|t| {
+                    let minutes = t.duration.as_secs() / 60;
+                    format!("{}h{:02}m", minutes / 60, minutes % 60)
+                })
+                .unwrap_or_default(),
+        ),
+        (
+            "resolution",
+            title.and_then(|t| t.resolution.clone()).unwrap_or_default(),
+        ),
+        (
+            "date",
+            meta.ripped_at
+                .map(|at| at.format("%Y-%m-%d").to_string())
+                .unwrap_or_default(),
+        ),
+        ("extension", args.extension.clone()),
+    ];
+
+    let mut template = args.template.as_ref().unwrap_or(&config.template);
+
+    if let MediaLookup::Matched { media } = &meta.lookup {
+        vars.push(("title", media.title.clone()));
+        vars.push((
+            "year",
+            media.year.map(|year| year.to_string()).unwrap_or_default(),
+        ));
+
+        template = match media.kind {
+            MediaKind::Movie => &config.movie_template,
+            MediaKind::Show => &config.show_template,
+        };
+    }
+
+    if let Some(series) = &meta.series
+        && let Some(episode) = series.episode(&file_name)
+    {
+        vars.push(("show", series.show.clone()));
+        vars.push(("season", format!("{:02}", series.season)));
+        vars.push(("episode", format!("{:02}", episode)));
+
+        template = &config.episode_template;
+    }
+
+    let vars: Vec<(&str, &str)> = vars
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+
+    naming::render(template, &vars)
+}
+
+/// Where an encode is written until it has been verified, so nothing
+/// half-written ever shows up under its real name.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// How long a video plays for, according to the configured probe.
+async fn probe_duration(probe: Probe, path: &Path) -> Result<Duration, Error> {
+    let output = match probe {
+        Probe::Ffprobe => {
+            Command::new("ffprobe")
+                .args([
+                    "-v",
+                    "error",
+                    "-show_entries",
+                    "format=duration",
+                    "-of",
+                    "default=noprint_wrappers=1:nokey=1",
+                ])
+                .arg(path)
+                .output()
+                .await?
+        }
+        Probe::Handbrake => {
+            Command::new("HandBrakeCLI")
+                .args(["--json", "--scan", "-i"])
+                .arg(path)
+                .output()
+                .await?
+        }
+    };
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "{:?} exited with {:?}",
+            probe,
+            output.status.code()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    match probe {
+        Probe::Ffprobe => Ok(Duration::from_secs_f64(stdout.trim().parse()?)),
+        Probe::Handbrake => {
+            let mut blocks = JsonBlocks::default();
+
+            let title_set = stdout
+                .lines()
+                .filter_map(|line| blocks.push(line))
+                .find(|(name, _)| name == "JSON Title Set")
+                .ok_or_else(|| format_err!("no titles found in {}", path.display()))?;
+
+            let title_set: TitleSet = serde_json::from_str(&title_set.1)?;
+            let duration = &title_set
+                .title_list
+                .first()
+                .ok_or_else(|| format_err!("no titles found in {}", path.display()))?
+                .duration;
+
+            Ok(Duration::from_secs(
+                duration.hours * 3600 + duration.minutes * 60 + duration.seconds,
+            ))
+        }
+    }
+}
+
+/// Checks a finished encode has something in it, can be read back and runs
+/// for as long as its source.
+async fn verify(config: &Verify, source: &Path, output: &Path) -> Result<(), Error> {
+    if fs::metadata(output).await?.len() == 0 {
+        return Err(VerifyError::Empty.into());
+    }
+
+    let output = probe_duration(config.probe, output)
+        .await
+        .map_err(VerifyError::Unreadable)?;
+
+    let source = probe_duration(config.probe, source)
+        .await
+        .map_err(|e| format_err!("unable to probe {}: {}", source.display(), e))?;
+
+    if source.abs_diff(output) > config.duration_tolerance {
+        return Err(VerifyError::Duration { source, output }.into());
+    }
+
+    Ok(())
+}
+
 async fn handbrake(
     config: &Handbrake,
     src: &Path,
     dest: &Path,
     job_id: &str,
-    jobs: &Arc<RwLock<HashMap<String, JobStatus>>>,
+    process: &HandbrakeProcess,
 ) -> Result<(), Error> {
+    let jobs = &process.jobs;
+
     let disc_meta: DiscMetadata = toml::from_slice(&fs::read(src.join("meta.toml")).await?)?;
 
     let args = match disc_meta.disc_type {
         DiscType::Dvd => &config.dvd,
         DiscType::BluRay => &config.bluray,
-        _ => unimplemented!(),
+        // audio CDs and data discs are finished when they're ripped
+        DiscType::Music | DiscType::Data => {
+            return Err(format_err!(
+                "unsupported disc type {} in {}",
+                disc_meta.disc_type,
+                src.display()
+            ));
+        }
+    };
+
+    let disc_info: Option<DiscInfo> = match fs::read(src.join("disc_info.json")).await {
+        Ok(json) => serde_json::from_slice(&json).ok(),
+        Err(_) => None,
     };
 
-    let dest = dest.join(src.file_name().unwrap());
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
 
-    fs::create_dir_all(&dest).await?;
+    let inputs = config
+        .inputs
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let mut files = fs::read_dir(src).await?;
+    let mut files = Vec::new();
+    let mut ignored = Vec::new();
+    let mut entries = fs::read_dir(src).await?;
 
-    while let Ok(Some(entry)) = files.next_entry().await {
-        if "toml" == entry.path().extension().unwrap().to_str().unwrap() {
-            continue;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = file_name(&entry.path());
+
+        if entry.file_type().await?.is_file() && inputs.iter().any(|input| input.matches(&name)) {
+            files.push(entry.path());
+        } else if !SIDECARS.contains(&name.as_str()) {
+            ignored.push(name);
+        }
+    }
+
+    files.sort();
+    ignored.sort();
+
+    if !ignored.is_empty() {
+        info!("ignoring {} in {}", ignored.join(", "), src.display());
+    }
+
+    if let Some(job_status) = jobs.write().await.get_mut(job_id) {
+        job_status.ignored = ignored;
+    }
+
+    if files.is_empty() {
+        return Err(format_err!(
+            "no files matching {} in {}",
+            config.inputs.join(", "),
+            src.display()
+        ));
+    }
+
+    // titles left out of a confirmed episode mapping aren't encoded
+    if let Some(series) = &disc_meta.series {
+        files.retain(|path| series.episode(&file_name(path)).is_some());
+    }
+
+    // keep what we know about files from an earlier attempt at this job
+    {
+        let mut jobs_map = jobs.write().await;
+        if let Some(job_status) = jobs_map.get_mut(job_id) {
+            let previous = std::mem::take(&mut job_status.files);
+
+            job_status.files = files
+                .iter()
+                .map(|path| {
+                    let source = path.display().to_string();
+                    previous
+                        .iter()
+                        .find(|file| file.source == source)
+                        .cloned()
+                        .unwrap_or(FileStatus {
+                            source,
+                            output: None,
+                            state: FileState::Pending,
+                        })
+                })
+                .collect();
+        }
+    }
+
+    let mut outputs = HashSet::new();
+    let mut errors = Vec::new();
+
+    for (index, path) in files.iter().enumerate() {
+        if process.is_cancelled(job_id) {
+            return Err(format_err!("cancelled"));
         }
 
-        let path = entry.path();
+        let source_file = path
+            .to_str()
+            .ok_or_else(|| format_err!("path is not a valid string: {:?}", path))?;
 
-        if path.is_file() {
-            let mut output_file = path.clone();
-            output_file.set_extension(&args.extension);
+        let previous = jobs
+            .read()
+            .await
+            .get(job_id)
+            .and_then(|job_status| job_status.files.get(index).cloned());
 
-            let source_file = path
-                .to_str()
-                .ok_or_else(|| format_err!("path is not a valid string: {:?}", path))?;
+        if let Some(FileStatus {
+            state: FileState::Done,
+            output: Some(output),
+            ..
+        }) = previous
+            && Path::new(&output).exists()
+        {
+            info!(
+                "{} was already encoded to {}, skipping",
+                source_file, output
+            );
+            outputs.insert(PathBuf::from(output));
+            continue;
+        }
+
+        let target = dest.join(output_path(
+            config,
+            args,
+            src,
+            path,
+            &disc_meta,
+            disc_info.as_ref(),
+        ));
 
-            let dest_file = dest.join(
-                output_file
-                    .file_name()
-                    .ok_or_else(|| format_err!("path is not a valid string: {:?}", dest))?,
+        // an encode left by an earlier job for the same rip counts as done
+        // too, as long as it checks out
+        if !outputs.contains(&target)
+            && target.exists()
+            && verify(&config.verify, path, &target).await.is_ok()
+        {
+            info!(
+                "{} already has an encode at {}, skipping",
+                source_file,
+                target.display()
             );
-            let dest_file = dest_file
-                .to_str()
-                .ok_or_else(|| format_err!("path is not a valid string: {:?}", dest_file))?;
 
-            // Update progress to indicate file processing started
+            if let Some(file) = jobs
+                .write()
+                .await
+                .get_mut(job_id)
+                .and_then(|job_status| job_status.files.get_mut(index))
             {
-                let mut jobs_map = jobs.write().await;
-                if let Some(job_status) = jobs_map.get_mut(job_id) {
-                    job_status.status = format!("Processing: {}", source_file);
-                    job_status.progress = 0.5; // Rough estimate
+                file.output = Some(target.display().to_string());
+                file.state = FileState::Done;
+            }
+
+            outputs.insert(target);
+            continue;
+        }
+
+        let dest_file =
+            naming::unique_path(&target, |path| outputs.contains(path) || path.exists());
+
+        if let Some(parent) = dest_file.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        outputs.insert(dest_file.clone());
+
+        let partial = partial_path(&dest_file);
+        let partial_file = partial
+            .to_str()
+            .ok_or_else(|| format_err!("path is not a valid string: {:?}", partial))?;
+
+        // Update progress to indicate file processing started
+        {
+            let mut jobs_map = jobs.write().await;
+            if let Some(job_status) = jobs_map.get_mut(job_id) {
+                if job_status.state != JobState::Paused {
+                    job_status.set_state(JobState::Running {
+                        file_index: index + 1,
+                        file_count: files.len(),
+                        current_file: Some(source_file.to_owned()),
+                    });
+                }
+                job_status.file_progress = 0.0;
+                job_status.progress = index as f32 / files.len() as f32;
+
+                if let Some(file) = job_status.files.get_mut(index) {
+                    file.output = Some(dest_file.display().to_string());
+                    file.state = FileState::Encoding;
                 }
             }
+        }
 
+        let result: Result<(), Error> = async {
             let mut child = Command::new("HandBrakeCLI")
-                .args(&[
+                .args([
+                    "--json",
                     "-i",
                     source_file,
                     "-o",
-                    dest_file,
+                    partial_file,
                     "--preset",
                     &args.preset,
                     "--subtitle",
@@ -198,32 +1278,206 @@ async fn handbrake(
                     "-F",
                 ])
                 .args(&args.args)
+                .args(
+                    config
+                        .threads
+                        .map(|threads| vec!["--encopts".to_owned(), format!("threads={}", threads)])
+                        .unwrap_or_default(),
+                )
+                .stdout(Stdio::piped())
                 .spawn()
-                .expect("failed to execute process");
+                .map_err(|e| format_err!("unable to run HandBrakeCLI: {}", e))?;
+
+            process.set_running_pid(job_id, child.id());
+
+            let stdout = child
+                .stdout
+                .take()
+                .ok_or_else(|| format_err!("unable to read HandBrakeCLI output"))?;
+            let mut lines = BufReader::new(stdout).lines();
+            let mut blocks = JsonBlocks::default();
+
+            while let Some(line) = lines.next_line().await? {
+                let Some((name, body)) = blocks.push(&line) else {
+                    continue;
+                };
+
+                if name != "Progress" {
+                    continue;
+                }
+
+                let progress: Progress = match serde_json::from_str(&body) {
+                    Ok(progress) => progress,
+                    Err(e) => {
+                        debug!("unable to parse HandBrake progress: {}", e);
+                        continue;
+                    }
+                };
+
+                let Some(working) = progress.working.filter(|_| progress.state == "WORKING") else {
+                    continue;
+                };
+
+                let mut jobs_map = jobs.write().await;
+                if let Some(job_status) = jobs_map.get_mut(job_id) {
+                    job_status.file_progress = working.progress;
+                    job_status.fps = working.rate;
+                    job_status.avg_fps = working.rate_avg;
+                    job_status.eta_seconds = u64::try_from(working.eta_seconds).ok();
+                    job_status.progress = (index as f32 + working.progress) / files.len() as f32;
+                }
+            }
 
             let status = child.wait().await?;
 
+            process.set_running_pid(job_id, None);
+
+            if process.is_cancelled(job_id) {
+                return Err(format_err!("cancelled"));
+            }
+
             if !status.success() {
-                return Err(format_err!(
-                    "error code {:?} from handbrake, stopping process",
-                    status.code()
-                ));
+                return Err(HandbrakeExit(status.code()).into());
+            }
+
+            verify(&config.verify, path, &partial).await?;
+
+            fs::rename(&partial, &dest_file).await?;
+
+            Ok(())
+        }
+        .await;
+
+        let cancelled = process.is_cancelled(job_id);
+
+        if result.is_err()
+            && partial.exists()
+            && let Err(e) = fs::remove_file(&partial).await
+        {
+            warn!("unable to remove partial output {}: {}", partial_file, e);
+        }
+
+        let mut jobs_map = jobs.write().await;
+        if let Some(file) = jobs_map
+            .get_mut(job_id)
+            .and_then(|job_status| job_status.files.get_mut(index))
+        {
+            file.state = match &result {
+                Ok(()) => FileState::Done,
+                Err(_) if cancelled => FileState::Pending,
+                Err(e) => FileState::Failed {
+                    reason: e.to_string(),
+                },
+            };
+        }
+        save_jobs(&process.store, &jobs_map).await;
+        drop(jobs_map);
+
+        match result {
+            Ok(()) => {}
+            Err(e) if cancelled => return Err(e),
+            Err(e) => {
+                warn!("unable to encode {}: {}", source_file, e);
+                errors.push(e);
             }
         }
     }
 
+    // the other files are still encoded, but the job only succeeds once all
+    // of them have been
+    if errors.len() == 1 {
+        return Err(errors.remove(0));
+    }
+
+    if !errors.is_empty() {
+        return Err(format_err!(
+            "{} of {} files failed to encode",
+            errors.len(),
+            files.len()
+        ));
+    }
+
     info!(
-        "finished handbake processing into directory {}",
-        dest.to_str().unwrap()
+        "finished handbake processing of {} into {}",
+        src.display(),
+        dest.display()
     );
 
-    let mut files = fs::read_dir(src).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if config.delete_on_complete {
-        while let Ok(Some(entry)) = files.next_entry().await {
-            fs::remove_file(entry.path()).await?;
-        }
+    fn collect(output: &str) -> Vec<(String, String)> {
+        let mut blocks = JsonBlocks::default();
+        output
+            .lines()
+            .filter_map(|line| blocks.push(line))
+            .collect()
     }
 
-    Ok(())
+    #[test]
+    fn collects_multi_line_progress() {
+        let output = r#"Progress: {
+    "State": "WORKING",
+    "Working": {
+        "ETASeconds": 95,
+        "Progress": 0.25,
+        "Rate": 120.5,
+        "RateAvg": 110.25
+    }
+}"#;
+
+        let blocks = collect(output);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, "Progress");
+
+        let progress: Progress = serde_json::from_str(&blocks[0].1).unwrap();
+        assert_eq!(progress.state, "WORKING");
+
+        let working = progress.working.unwrap();
+        assert_eq!(working.progress, 0.25);
+        assert_eq!(working.rate, 120.5);
+        assert_eq!(working.rate_avg, 110.25);
+        assert_eq!(working.eta_seconds, 95);
+    }
+
+    #[test]
+    fn collects_single_line_blocks() {
+        let blocks = collect(r#"Version: {"Name": "HandBrake"}"#);
+        assert_eq!(
+            blocks,
+            vec![("Version".to_owned(), r#"{"Name": "HandBrake"}"#.to_owned())]
+        );
+    }
+
+    #[test]
+    fn skips_log_lines_between_blocks() {
+        let output = r#"[12:00:01] hb_init: starting libhb thread
+Progress: {
+    "State": "SCANNING"
+}
+[12:00:02] scan: decoding previews for title 1
+JSON Title Set: {
+    "TitleList": [
+        {"Duration": {"Hours": 1, "Minutes": 2, "Seconds": 3}}
+    ]
+}"#;
+
+        let blocks = collect(output);
+        let names = blocks
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Progress", "JSON Title Set"]);
+
+        let progress: Progress = serde_json::from_str(&blocks[0].1).unwrap();
+        assert_eq!(progress.state, "SCANNING");
+        assert!(progress.working.is_none());
+
+        let title_set: serde_json::Value = serde_json::from_str(&blocks[1].1).unwrap();
+        assert_eq!(title_set["TitleList"][0]["Duration"]["Minutes"], 2);
+    }
 }
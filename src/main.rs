@@ -1,20 +1,41 @@
-use std::{env, path::Path};
+use std::{collections::HashMap, env, path::Path};
 
 use argh::FromArgs;
 use failure::Error;
 use futures::future::try_join_all;
 use log::{error, info, warn};
-use tokio::time::sleep;
-use tokio::{fs, task::JoinHandle};
+use tokio::{fs, task, task::JoinHandle};
 
-use crate::config::Settings;
-use crate::disc::{Disc, DiscType};
+use crate::catalog::{Catalog, CatalogEntry, Decision, EncodeSettings, Outcome};
+use crate::cdrom::TrayLocks;
+use crate::config::{Detection, DuplicatePolicy, SelectionStrategy, Settings};
+use crate::disc::{Disc, DiscMetadata, DiscType};
 use crate::handbrake::HandbrakeProcess;
-
+use crate::makemkv::{RipStatus, RipStatuses};
+use crate::metadata::{MediaKind, MediaLookup};
+use crate::monitor::DiscWatcher;
+use crate::music::Reviews;
+use crate::series::SeriesStore;
+use crate::store::JobStore;
+
+mod catalog;
+mod cdrom;
+mod command;
 mod config;
+mod data;
 mod disc;
+mod fingerprint;
 mod handbrake;
 mod makemkv;
+mod metadata;
+mod monitor;
+mod music;
+mod musicbrainz;
+mod naming;
+mod queue;
+mod series;
+mod store;
+mod udf;
 mod web;
 
 #[tokio::main]
@@ -37,13 +58,36 @@ async fn main() -> Result<(), Error> {
         Command::Rip(_) => {
             rip(settings).await?;
         }
+        Command::Catalog(CommandCatalog { query, json }) => {
+            let catalog = Catalog::load(Path::new(&settings.directory.state)).await?;
+            let entries = catalog.search(query.as_deref().unwrap_or_default()).await;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                for entry in entries {
+                    println!(
+                        "{}  {:<8} {:<30} {:<10} {}",
+                        entry.started_at.format("%Y-%m-%d %H:%M"),
+                        entry.disc_type.to_string(),
+                        entry.title,
+                        entry.outcome.to_string(),
+                        entry
+                            .output
+                            .map(|output| output.display().to_string())
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
         Command::Debug(_) => {
             println!("Settings: {:#?}", settings);
 
             for device in settings.options.devices {
-                let disc = Disc::new(&device);
-
-                println!("{:#?}", disc);
+                match Disc::new(&device) {
+                    Ok(disc) => println!("{:#?}", disc),
+                    Err(err) => println!("{}: {}", device, err),
+                }
             }
         }
     }
@@ -52,19 +96,62 @@ async fn main() -> Result<(), Error> {
 }
 
 async fn rip(settings: Settings) -> Result<(), Error> {
-    let (hb_process, hb_handle) = HandbrakeProcess::new(settings.handbrake.clone());
+    let job_store = JobStore::new(Path::new(&settings.directory.state));
+    let catalog = Catalog::load(Path::new(&settings.directory.state)).await?;
+    let (hb_process, hb_handle) =
+        HandbrakeProcess::new(settings.handbrake.clone(), job_store, catalog.clone()).await?;
+
+    let series = SeriesStore::load(
+        Path::new(&settings.directory.state),
+        Path::new(&settings.directory.raw),
+    )
+    .await?;
 
-    process_existing_directories(&hb_process, &settings).await?;
+    process_existing_directories(&hb_process, &series, &settings).await?;
 
-    let mut handles = Vec::with_capacity(settings.options.devices.len() + 2);
+    let mut handles = Vec::with_capacity(settings.options.devices.len() + 3);
 
     handles.push(hb_handle);
 
+    let mut media_events = HashMap::new();
+
+    if settings.options.detection == Detection::Udev {
+        match monitor::spawn_udev_monitor(&settings.options.devices) {
+            Ok((receivers, handle)) => {
+                media_events = receivers;
+                handles.push(handle);
+            }
+            Err(err) => warn!(
+                "Unable to start udev monitor, falling back to polling: {}",
+                err
+            ),
+        }
+    }
+
+    let rip_statuses = RipStatuses::default();
+    let reviews = music::load_reviews(Path::new(&settings.directory.output)).await?;
+
     // Start web interface in background
     let web_settings = settings.clone();
     let web_hb_process = hb_process.clone();
+    let web_rip_statuses = rip_statuses.clone();
+    let web_reviews = reviews.clone();
+    let web_series = series.clone();
+    let web_catalog = catalog.clone();
+    let tray_locks = TrayLocks::default();
+    let web_tray_locks = tray_locks.clone();
     let web_handle = tokio::spawn(async move {
-        if let Err(e) = web::run_web_server(web_settings, web_hb_process).await {
+        if let Err(e) = web::run_web_server(
+            web_settings,
+            web_hb_process,
+            web_rip_statuses,
+            web_reviews,
+            web_series,
+            web_catalog,
+            web_tray_locks,
+        )
+        .await
+        {
             warn!("Web interface error: {}", e);
         }
         Ok(())
@@ -73,11 +160,23 @@ async fn rip(settings: Settings) -> Result<(), Error> {
 
     info!("Web interface started at http://localhost:8080");
 
+    let context = Context {
+        settings: settings.clone(),
+        hb_process,
+        rip_statuses,
+        reviews,
+        series,
+        catalog,
+        tray_locks,
+    };
+
     for device in settings.options.devices.clone() {
-        let settings = settings.clone();
-        let hb_process = hb_process.clone();
+        let watcher = match media_events.remove(&device) {
+            Some(rx) => DiscWatcher::Udev(rx, settings.options.sleep_time),
+            None => DiscWatcher::Poll(settings.options.sleep_time),
+        };
 
-        let handle = spawn_rip_process(device, settings, hb_process);
+        let handle = spawn_rip_process(device, context.clone(), watcher);
 
         handles.push(handle);
     }
@@ -95,49 +194,307 @@ async fn rip(settings: Settings) -> Result<(), Error> {
     Ok(())
 }
 
-fn spawn_rip_process(
-    device: String,
+/// Everything the rip loops share.
+#[derive(Clone)]
+struct Context {
     settings: Settings,
     hb_process: HandbrakeProcess,
+    rip_statuses: RipStatuses,
+    reviews: Reviews,
+    series: SeriesStore,
+    catalog: Catalog,
+    tray_locks: TrayLocks,
+}
+
+fn spawn_rip_process(
+    device: String,
+    context: Context,
+    mut watcher: DiscWatcher,
 ) -> JoinHandle<Result<(), Error>> {
     tokio::spawn(async move {
         loop {
-            let device = device.to_owned();
-            let raw = Path::new(&settings.directory.raw);
-            let dest = Path::new(&settings.directory.output);
-            let disc = Disc::new(&device);
-
-            if fs::File::open(device).await.is_ok() {
-                match &disc.r#type {
-                    Some(DiscType::Dvd) | Some(DiscType::BluRay) => {
-                        let rip_target_folder = raw.join(disc.path_friendly_title());
-                        println!("{}", rip_target_folder.display());
-                        let rip_target_folder =
-                            makemkv::rip(&settings.makemkv, &disc, &rip_target_folder).await?;
-                        hb_process
-                            .queue(rip_target_folder, dest.to_path_buf())
-                            .await?;
-                        info!("Finished ripping disc!");
-                        disc::eject(&disc).await;
-                    }
-                    Some(t) => {
-                        warn!("Disc type {:?} currently unsupported", t);
-                        disc::eject(&disc).await;
-                    }
-                    None => {
-                        warn!("Unknown disc type");
-                        disc::eject(&disc).await;
-                    }
+            let ready = monitor::disc_ready(&device).await;
+
+            if ready {
+                // forget how the last disc went now there's a new one
+                context.rip_statuses.write().await.remove(&device);
+            }
+
+            if ready && let Err(err) = process_disc(&device, &context).await {
+                error!("Error processing disc in {}: {}", device, err);
+
+                context.rip_statuses.write().await.insert(
+                    device.clone(),
+                    RipStatus {
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    },
+                );
+
+                // leaving the disc in would have it picked up again straight away
+                if let Err(err) = disc::eject(&device).await {
+                    warn!("{}", err);
                 }
             }
 
-            sleep(settings.options.sleep_time).await;
+            watcher.wait_for_insert(&device).await;
         }
     })
 }
 
+/// Looks the disc up in the catalog and applies `options.duplicates` if it's
+/// been ripped before. Returns false if the disc should be skipped.
+async fn check_duplicate(device: &str, disc: &Disc, fingerprint: &str, context: &Context) -> bool {
+    let Some(previous) = context.catalog.find(fingerprint).await else {
+        return true;
+    };
+
+    info!(
+        "{} was already ripped on {}",
+        disc.title(),
+        previous.started_at.format("%Y-%m-%d %H:%M")
+    );
+
+    let decision = match context.settings.options.duplicates {
+        DuplicatePolicy::Rerip => Decision::Rerip,
+        DuplicatePolicy::Skip => Decision::Skip,
+        DuplicatePolicy::Ask => {
+            context.rip_statuses.write().await.insert(
+                device.to_owned(),
+                RipStatus {
+                    operation: Some("Already ripped, waiting for a decision".to_owned()),
+                    ..Default::default()
+                },
+            );
+
+            let decision = context.catalog.ask(device, &disc.title(), previous).await;
+
+            context.rip_statuses.write().await.remove(device);
+
+            decision
+        }
+    };
+
+    decision == Decision::Rerip
+}
+
+/// Rips the disc in a drive, recording how it went in the catalog.
+async fn process_disc(device: &str, context: &Context) -> Result<(), Error> {
+    let disc = Disc::new(device)?;
+    let disc_type = disc.disc_type()?;
+
+    let fingerprint = {
+        let path = device.to_owned();
+        match task::spawn_blocking(move || fingerprint::fingerprint(&path, disc_type)).await? {
+            Ok(fingerprint) => Some(fingerprint),
+            Err(e) => {
+                warn!("Unable to fingerprint {}: {}", device, e);
+                None
+            }
+        }
+    };
+
+    let mut entry = CatalogEntry::new(&disc, disc_type, fingerprint.clone());
+
+    if let Some(fingerprint) = &fingerprint
+        && !check_duplicate(device, &disc, fingerprint, context).await
+    {
+        info!("Skipping {}", disc.title());
+        entry.finish(Outcome::Skipped);
+        context.catalog.record(entry).await?;
+        disc::eject(device).await?;
+        return Ok(());
+    }
+
+    context.catalog.record(entry.clone()).await?;
+
+    let result = {
+        // stop the disc being pulled out from under the rip
+        let _lock = context
+            .tray_locks
+            .hold(device)
+            .inspect_err(|e| warn!("Unable to lock the tray of {}: {}", device, e));
+
+        rip_disc(device, &disc, context, &mut entry).await
+    };
+
+    if let Some(output) = &entry.output {
+        entry.output_bytes = catalog::disk_usage(output).await;
+    }
+
+    entry.finish(match &result {
+        Ok(()) => Outcome::Ripped,
+        Err(e) => Outcome::Failed {
+            reason: e.to_string(),
+        },
+    });
+
+    context.catalog.record(entry).await?;
+
+    result?;
+
+    disc::eject(device).await?;
+
+    Ok(())
+}
+
+async fn rip_disc(
+    device: &str,
+    disc: &Disc,
+    context: &Context,
+    entry: &mut CatalogEntry,
+) -> Result<(), Error> {
+    let settings = &context.settings;
+    let hb_process = &context.hb_process;
+    let rip_statuses = &context.rip_statuses;
+    let series = &context.series;
+
+    let raw = Path::new(&settings.directory.raw);
+    let dest = Path::new(&settings.directory.output);
+    let logs = Path::new(&settings.directory.logs);
+    let disc_type = entry.disc_type;
+
+    match disc_type {
+        DiscType::Dvd | DiscType::BluRay => {
+            let rip_target_folder = raw.join(disc.path_friendly_title());
+            info!(
+                "Ripping {} into {}",
+                disc.title(),
+                rip_target_folder.display()
+            );
+
+            rip_statuses
+                .write()
+                .await
+                .insert(device.to_owned(), Default::default());
+
+            let disc_info = makemkv::scan(&settings.makemkv, disc, logs, rip_statuses).await?;
+
+            let mut series_disc = series::detect(disc, &disc_info, &settings.makemkv.selection);
+
+            let label = match &series_disc {
+                Some(series_disc) => series_disc.show.clone(),
+                None => disc.title(),
+            };
+            let lookup = metadata::lookup(&settings.metadata, &label).await;
+
+            let mut makemkv = settings.makemkv.clone();
+            let mut mapping = None;
+
+            if let Some(series_disc) = &mut series_disc {
+                if let MediaLookup::Matched { media } = &lookup
+                    && media.kind == MediaKind::Show
+                {
+                    series_disc.show = media.title.clone();
+                }
+
+                makemkv.selection.strategy = SelectionStrategy::Episodes;
+
+                let titles = disc_info.select_titles(&makemkv.selection);
+                mapping = Some(series.propose(series_disc, &disc_info, &titles).await);
+            }
+
+            let meta = DiscMetadata {
+                disc_type,
+                title: Some(disc.title()),
+                ripped_at: Some(disc.detected_at),
+                lookup,
+                series: mapping.clone(),
+            };
+
+            let args = match disc_type {
+                DiscType::Dvd => &settings.handbrake.dvd,
+                _ => &settings.handbrake.bluray,
+            };
+
+            entry.titles = disc_info.select_titles(&makemkv.selection);
+            entry.encode = Some(EncodeSettings {
+                preset: args.preset.clone(),
+                extension: args.extension.clone(),
+                args: args.args.clone(),
+            });
+
+            let rip_target_folder = makemkv::rip(
+                &makemkv,
+                disc,
+                &disc_info,
+                &meta,
+                &rip_target_folder,
+                logs,
+                rip_statuses,
+            )
+            .await?;
+
+            entry.output = Some(rip_target_folder.clone());
+
+            rip_statuses.write().await.remove(device);
+
+            match mapping {
+                Some(mapping) => {
+                    series.record(&mapping).await?;
+
+                    info!(
+                        "Episode mapping for {} is waiting to be confirmed",
+                        rip_target_folder.display()
+                    );
+                    series.park(rip_target_folder, mapping).await;
+                }
+                None => {
+                    hb_process
+                        .queue(rip_target_folder, dest.to_path_buf())
+                        .await?;
+                }
+            }
+            info!("Finished ripping disc!");
+        }
+        DiscType::Music => {
+            rip_statuses
+                .write()
+                .await
+                .insert(device.to_owned(), Default::default());
+
+            let album_dir = music::rip(
+                &settings.music,
+                disc,
+                raw,
+                dest,
+                rip_statuses,
+                &context.reviews,
+            )
+            .await?;
+
+            rip_statuses.write().await.remove(device);
+
+            info!(
+                "Finished ripping {} into {}",
+                disc.title(),
+                album_dir.display()
+            );
+
+            entry.output = Some(album_dir);
+        }
+        DiscType::Data => {
+            rip_statuses
+                .write()
+                .await
+                .insert(device.to_owned(), Default::default());
+
+            let iso = data::archive(&settings.data, disc, dest, logs, rip_statuses).await?;
+
+            rip_statuses.write().await.remove(device);
+
+            info!("Finished imaging {} into {}", disc.title(), iso.display());
+
+            entry.output = Some(iso);
+        }
+    }
+
+    Ok(())
+}
+
 async fn process_existing_directories(
     hb_process: &HandbrakeProcess,
+    series: &SeriesStore,
     settings: &Settings,
 ) -> Result<(), Error> {
     if settings.makemkv.enqueue_existing_jobs {
@@ -148,9 +505,12 @@ async fn process_existing_directories(
         let mut folders = fs::read_dir(&settings.directory.raw).await?;
 
         while let Ok(Some(entry)) = folders.next_entry().await {
-            if entry.path().is_dir() && entry.path().join("meta.toml").is_file() {
+            if entry.path().is_dir()
+                && entry.path().join("meta.toml").is_file()
+                && !series.is_pending(&entry.path()).await
+            {
                 hb_process
-                    .queue(
+                    .enqueue_existing(
                         entry.path(),
                         Path::new(&settings.directory.output).to_path_buf(),
                     )
@@ -173,6 +533,7 @@ struct Args {
 #[argh(subcommand)]
 enum Command {
     Rip(CommandRIP),
+    Catalog(CommandCatalog),
     Debug(CommandDebug),
 }
 
@@ -181,6 +542,19 @@ enum Command {
 #[argh(subcommand, name = "rip")]
 struct CommandRIP {}
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// lists every disc that has been processed, newest first.
+#[argh(subcommand, name = "catalog")]
+struct CommandCatalog {
+    /// only show discs whose title, type, drive, outcome or paths contain this
+    #[argh(positional)]
+    query: Option<String>,
+
+    /// print the entries as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// prints debug information about disc.
 #[argh(subcommand, name = "debug")]
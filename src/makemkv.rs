@@ -1,56 +1,842 @@
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
-    time::SystemTime,
+    process::Stdio,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use failure::{format_err, Error};
-use tokio::{fs, process::Command};
+use failure::{Error, format_err};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::RwLock,
+};
 
-use crate::config::MakeMKV;
+use crate::config::{MakeMKV, Selection, SelectionStrategy};
 use crate::disc::{Disc, DiscMetadata};
 
-pub async fn rip(config: &MakeMKV, disc: &Disc, target_folder: &Path) -> Result<PathBuf, Error> {
-    let target_folder = {
+/// Titles within this much of the longest title are considered candidates
+/// for the main feature.
+const MAIN_FEATURE_TOLERANCE: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RipStatus {
+    pub title_index: usize,
+    pub title_count: usize,
+    pub operation: Option<String>,
+    pub current_progress: f32,
+    pub total_progress: f32,
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl RipStatus {
+    fn apply(&mut self, event: &RobotEvent) {
+        match event {
+            RobotEvent::CurrentOperation(name) => self.operation = Some(name.clone()),
+            RobotEvent::TotalOperation(name) => {
+                if self.operation.is_none() {
+                    self.operation = Some(name.clone());
+                }
+            }
+            RobotEvent::Progress {
+                current,
+                total,
+                max,
+            } => {
+                if *max > 0 {
+                    self.current_progress = *current as f32 / *max as f32;
+                    self.total_progress = *total as f32 / *max as f32;
+                }
+            }
+            RobotEvent::Message { flags, message, .. } => {
+                if *flags != 0 {
+                    self.warnings.push(message.clone());
+                }
+            }
+            RobotEvent::DiscInfo { .. }
+            | RobotEvent::TitleInfo { .. }
+            | RobotEvent::StreamInfo { .. } => {}
+        }
+    }
+}
+
+/// Attribute ids used in `CINFO`, `TINFO` and `SINFO` records, from
+/// MakeMKV's `apdefs.h`.
+mod attribute {
+    pub const TYPE: u32 = 1;
+    pub const NAME: u32 = 2;
+    pub const LANG_CODE: u32 = 3;
+    pub const LANG_NAME: u32 = 4;
+    pub const CODEC_SHORT: u32 = 6;
+    pub const CHAPTER_COUNT: u32 = 8;
+    pub const DURATION: u32 = 9;
+    pub const DISK_SIZE_BYTES: u32 = 11;
+    pub const AUDIO_CHANNELS_COUNT: u32 = 14;
+    pub const SOURCE_FILE_NAME: u32 = 16;
+    pub const VIDEO_SIZE: u32 = 19;
+    pub const SEGMENTS_MAP: u32 = 26;
+    pub const OUTPUT_FILE_NAME: u32 = 27;
+    pub const VOLUME_NAME: u32 = 32;
+}
+
+/// What `makemkvcon info` reports about a disc, written alongside
+/// `meta.toml` as `disc_info.json`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DiscInfo {
+    pub name: Option<String>,
+    pub volume_name: Option<String>,
+    pub titles: Vec<TitleInfo>,
+    #[serde(default)]
+    pub selected_titles: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TitleInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    pub chapter_count: u32,
+    pub size_bytes: u64,
+    pub segment_map: String,
+    pub source_file_name: Option<String>,
+    pub output_file_name: Option<String>,
+    pub resolution: Option<String>,
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub language_code: Option<String>,
+    pub language_name: Option<String>,
+    pub channels: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitles,
+    #[default]
+    Other,
+}
+
+impl DiscInfo {
+    fn apply(&mut self, event: &RobotEvent) {
+        match event {
+            RobotEvent::DiscInfo { attribute, value } => match *attribute {
+                attribute::NAME => self.name = Some(value.clone()),
+                attribute::VOLUME_NAME => self.volume_name = Some(value.clone()),
+                _ => {}
+            },
+            RobotEvent::TitleInfo {
+                title,
+                attribute,
+                value,
+            } => self.title_mut(*title).apply(*attribute, value),
+            RobotEvent::StreamInfo {
+                title,
+                stream,
+                attribute,
+                value,
+            } => {
+                let title = self.title_mut(*title);
+                if *attribute == attribute::VIDEO_SIZE {
+                    title.resolution = Some(value.clone());
+                }
+                title.stream_mut(*stream).apply(*attribute, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn title_mut(&mut self, id: usize) -> &mut TitleInfo {
+        while self.titles.len() <= id {
+            let id = self.titles.len();
+            self.titles.push(TitleInfo {
+                id,
+                ..Default::default()
+            });
+        }
+
+        &mut self.titles[id]
+    }
+
+    /// Picks which titles to rip according to the configured strategy.
+    pub fn select_titles(&self, selection: &Selection) -> Vec<usize> {
+        match selection.strategy {
+            SelectionStrategy::MainFeature => self.main_feature().into_iter().collect(),
+            SelectionStrategy::Episodes => {
+                self.episodes(selection.episode_min_length, selection.episode_max_length)
+            }
+            SelectionStrategy::AllAbove => self
+                .titles
+                .iter()
+                .filter(|t| t.duration >= selection.min_length)
+                .map(|t| t.id)
+                .collect(),
+            SelectionStrategy::Titles => selection
+                .titles
+                .iter()
+                .copied()
+                .filter(|id| *id < self.titles.len())
+                .collect(),
+        }
+    }
+
+    /// Finds the longest title. Obfuscated Blu-rays ship dozens of playlists
+    /// of the same length that only differ in segment order, so when there is
+    /// more than one candidate prefer the one that plays its segments in
+    /// order, then the one with the most chapters.
+    fn main_feature(&self) -> Option<usize> {
+        let longest = self.titles.iter().map(|t| t.duration).max()?;
+
+        let mut candidates: Vec<&TitleInfo> = Vec::new();
+        for title in &self.titles {
+            if longest - title.duration <= MAIN_FEATURE_TOLERANCE
+                && !candidates
+                    .iter()
+                    .any(|c| c.segment_map == title.segment_map)
+            {
+                candidates.push(title);
+            }
+        }
+
+        if candidates.len() > 1 {
+            warn!(
+                "{} candidate main feature titles, picking the most likely one",
+                candidates.len()
+            );
+        }
+
+        candidates
+            .into_iter()
+            .max_by_key(|t| {
+                (
+                    segments_in_order(&t.segment_map),
+                    t.chapter_count,
+                    std::cmp::Reverse(t.id),
+                )
+            })
+            .map(|t| t.id)
+    }
+
+    /// Titles that look like episodes, skipping any that play the same
+    /// segments as an earlier title.
+    fn episodes(&self, min_length: Duration, max_length: Duration) -> Vec<usize> {
+        let mut seen = Vec::new();
+        let mut episodes = Vec::new();
+
+        for title in &self.titles {
+            if title.duration < min_length || title.duration > max_length {
+                continue;
+            }
+
+            if !title.segment_map.is_empty() && seen.contains(&&title.segment_map) {
+                continue;
+            }
+
+            seen.push(&title.segment_map);
+            episodes.push(title.id);
+        }
+
+        episodes
+    }
+}
+
+fn segments_in_order(segment_map: &str) -> bool {
+    let segments: Vec<u32> = segment_map
+        .split([',', '-'])
+        .filter_map(|s| s.trim().parse().ok())
+        .collect();
+
+    segments.windows(2).all(|w| w[0] <= w[1])
+}
+
+impl TitleInfo {
+    fn apply(&mut self, attribute: u32, value: &str) {
+        match attribute {
+            attribute::NAME => self.name = Some(value.to_owned()),
+            attribute::CHAPTER_COUNT => self.chapter_count = value.parse().unwrap_or_default(),
+            attribute::DURATION => self.duration = parse_duration(value).unwrap_or_default(),
+            attribute::DISK_SIZE_BYTES => self.size_bytes = value.parse().unwrap_or_default(),
+            attribute::SEGMENTS_MAP => self.segment_map = value.to_owned(),
+            attribute::SOURCE_FILE_NAME => self.source_file_name = Some(value.to_owned()),
+            attribute::OUTPUT_FILE_NAME => self.output_file_name = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    fn stream_mut(&mut self, index: usize) -> &mut StreamInfo {
+        if self.streams.len() <= index {
+            self.streams.resize_with(index + 1, Default::default);
+        }
+
+        &mut self.streams[index]
+    }
+}
+
+impl StreamInfo {
+    fn apply(&mut self, attribute: u32, value: &str) {
+        match attribute {
+            attribute::TYPE => {
+                self.kind = match value {
+                    "Video" => StreamKind::Video,
+                    "Audio" => StreamKind::Audio,
+                    "Subtitles" => StreamKind::Subtitles,
+                    _ => StreamKind::Other,
+                }
+            }
+            attribute::CODEC_SHORT => self.codec = Some(value.to_owned()),
+            attribute::LANG_CODE => self.language_code = Some(value.to_owned()),
+            attribute::LANG_NAME => self.language_name = Some(value.to_owned()),
+            attribute::AUDIO_CHANNELS_COUNT => self.channels = value.parse().ok(),
+            _ => {}
+        }
+    }
+}
+
+/// Parses the `h:mm:ss` durations makemkvcon reports for titles.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut seconds = 0;
+
+    for part in value.split(':') {
+        seconds = seconds * 60 + part.trim().parse::<u64>().ok()?;
+    }
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// Rip progress keyed by device name.
+pub type RipStatuses = Arc<RwLock<HashMap<String, RipStatus>>>;
+
+/// A single line of `makemkvcon -r` (robot mode) output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RobotEvent {
+    CurrentOperation(String),
+    TotalOperation(String),
+    Progress {
+        current: u32,
+        total: u32,
+        max: u32,
+    },
+    Message {
+        code: u32,
+        flags: u32,
+        message: String,
+    },
+    DiscInfo {
+        attribute: u32,
+        value: String,
+    },
+    TitleInfo {
+        title: usize,
+        attribute: u32,
+        value: String,
+    },
+    StreamInfo {
+        title: usize,
+        stream: usize,
+        attribute: u32,
+        value: String,
+    },
+}
+
+impl RobotEvent {
+    pub fn parse(line: &str) -> Option<RobotEvent> {
+        let (kind, rest) = line.split_once(':')?;
+        let fields = split_fields(rest);
+        let field = |i: usize| fields.get(i).map(String::as_str);
+
+        match kind {
+            "PRGC" => Some(RobotEvent::CurrentOperation(field(2)?.to_owned())),
+            "PRGT" => Some(RobotEvent::TotalOperation(field(2)?.to_owned())),
+            "PRGV" => Some(RobotEvent::Progress {
+                current: parse_field(&fields, 0)?,
+                total: parse_field(&fields, 1)?,
+                max: parse_field(&fields, 2)?,
+            }),
+            "MSG" => Some(RobotEvent::Message {
+                code: parse_field(&fields, 0)?,
+                flags: parse_field(&fields, 1)?,
+                message: field(3)?.to_owned(),
+            }),
+            "CINFO" => Some(RobotEvent::DiscInfo {
+                attribute: parse_field(&fields, 0)?,
+                value: field(2)?.to_owned(),
+            }),
+            "TINFO" => Some(RobotEvent::TitleInfo {
+                title: parse_field(&fields, 0)?,
+                attribute: parse_field(&fields, 1)?,
+                value: field(3)?.to_owned(),
+            }),
+            "SINFO" => Some(RobotEvent::StreamInfo {
+                title: parse_field(&fields, 0)?,
+                stream: parse_field(&fields, 1)?,
+                attribute: parse_field(&fields, 2)?,
+                value: field(4)?.to_owned(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn parse_field<T: FromStr>(fields: &[String], index: usize) -> Option<T> {
+    fields.get(index)?.parse().ok()
+}
+
+/// Splits a robot mode record into its comma separated fields, unquoting
+/// any quoted strings along the way.
+fn split_fields(record: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = record.chars();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                if let Some(escaped) = chars.next() {
+                    field.push(escaped);
+                }
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    fields.push(field);
+    fields
+}
+
+/// Runs `makemkvcon info` against the disc to find out which titles and
+/// streams are on it before anything gets ripped. It's given the same `args`
+/// as the rip, since options like `--minlength` change the title numbering.
+pub async fn scan(
+    config: &MakeMKV,
+    disc: &Disc,
+    log_dir: &Path,
+    statuses: &RipStatuses,
+) -> Result<DiscInfo, Error> {
+    let log_path = log_dir.join(format!("{}_info.log", disc.path_friendly_title()));
+    let mut disc_info = DiscInfo::default();
+
+    let mut args = vec![
+        "info".to_owned(),
+        "-r".to_owned(),
+        "--progress=-same".to_owned(),
+        format!("dev:{}", disc.name),
+    ];
+    args.extend(config.args.iter().cloned());
+
+    run_makemkvcon(&args, &log_path, disc, statuses, |event| {
+        disc_info.apply(event)
+    })
+    .await?;
+
+    Ok(disc_info)
+}
+
+pub async fn rip(
+    config: &MakeMKV,
+    disc: &Disc,
+    disc_info: &DiscInfo,
+    meta: &DiscMetadata,
+    target_folder: &Path,
+    log_dir: &Path,
+    statuses: &RipStatuses,
+) -> Result<PathBuf, Error> {
+    let folder_name = {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_secs();
 
-        target_folder.with_file_name(format!(
-            "{}_{}",
-            target_folder.file_name().unwrap().to_str().unwrap(),
-            timestamp,
-        ))
+        let title = target_folder
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format_err!("invalid rip folder {:?}", target_folder))?;
+
+        format!("{}_{}", title, timestamp)
     };
 
+    let target_folder = target_folder.with_file_name(&folder_name);
+
     fs::create_dir_all(&target_folder).await?;
 
+    let log_path = log_dir.join(format!("{}.log", folder_name));
+
+    let titles = disc_info.select_titles(&config.selection);
+
+    if titles.is_empty() {
+        return Err(format_err!(
+            "no titles on {} matched the {:?} selection",
+            disc.name,
+            config.selection.strategy
+        ));
+    }
+
+    info!("ripping titles {:?} from {}", titles, disc.name);
+
+    for (index, title) in titles.iter().enumerate() {
+        if let Some(status) = statuses.write().await.get_mut(&disc.name) {
+            status.title_index = index + 1;
+            status.title_count = titles.len();
+        }
+
+        let mut args = vec![
+            "mkv".to_owned(),
+            "-r".to_owned(),
+            "--progress=-same".to_owned(),
+            format!("dev:{}", disc.name),
+            title.to_string(),
+            target_folder.to_string_lossy().into_owned(),
+        ];
+        args.extend(config.args.iter().cloned());
+
+        run_makemkvcon(&args, &log_path, disc, statuses, |_| {}).await?;
+    }
+
+    let toml = toml::to_string(meta)?;
+
+    fs::write(target_folder.join("meta.toml"), toml).await?;
+    let disc_info = DiscInfo {
+        selected_titles: titles,
+        ..disc_info.clone()
+    };
+
+    fs::write(
+        target_folder.join("disc_info.json"),
+        serde_json::to_string_pretty(&disc_info)?,
+    )
+    .await?;
+
+    Ok(target_folder)
+}
+
+/// Runs makemkvcon in robot mode, logging its output to `log_path` and
+/// keeping the drive's rip status up to date as events come in.
+async fn run_makemkvcon(
+    args: &[String],
+    log_path: &Path,
+    disc: &Disc,
+    statuses: &RipStatuses,
+    mut on_event: impl FnMut(&RobotEvent),
+) -> Result<(), Error> {
+    if let Some(log_dir) = log_path.parent() {
+        fs::create_dir_all(log_dir).await?;
+    }
+
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .await?;
+
     let mut child = Command::new("makemkvcon")
-        .args(&[
-            "mkv",
-            "-r",
-            &format!("dev:{}", disc.name),
-            "all",
-            target_folder.to_str().unwrap(),
-            "--minlength=600",
-        ])
-        .args(&config.args)
+        .args(args)
+        .stdout(Stdio::piped())
         .spawn()
-        .expect("failed to execute process");
+        .map_err(|e| format_err!("unable to run makemkvcon: {}", e))?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| format_err!("unable to read makemkvcon output"))?;
+    let mut lines = BufReader::new(stdout).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        log.write_all(line.as_bytes()).await?;
+        log.write_all(b"\n").await?;
+
+        let Some(event) = RobotEvent::parse(&line) else {
+            continue;
+        };
+
+        debug!("makemkv: {:?}", event);
+
+        if let RobotEvent::Message {
+            flags, ref message, ..
+        } = event
+            && flags != 0
+        {
+            warn!("makemkv: {}", message);
+        }
+
+        on_event(&event);
+
+        let mut statuses = statuses.write().await;
+        if let Some(status) = statuses.get_mut(&disc.name) {
+            status.apply(&event);
+        }
+    }
 
     let status = child.wait().await?;
 
     if !status.success() {
         return Err(format_err!(
-            "error code {:?} from handbrake, stopping process",
-            status.code()
+            "error code {:?} from makemkv, see {}",
+            status.code(),
+            log_path.display()
         ));
     }
 
-    let toml = toml::to_string(&DiscMetadata {
-        disc_type: disc.r#type.unwrap(),
-    })?;
+    Ok(())
+}
 
-    fs::write(target_folder.join("meta.toml"), toml).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(target_folder)
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(
+            split_fields(r#"1,0,"a, \"quoted\" value","""#),
+            vec!["1", "0", r#"a, "quoted" value"#, ""]
+        );
+    }
+
+    #[test]
+    fn parses_progress() {
+        assert_eq!(
+            RobotEvent::parse("PRGV:1024,2048,65536"),
+            Some(RobotEvent::Progress {
+                current: 1024,
+                total: 2048,
+                max: 65536,
+            })
+        );
+        assert_eq!(
+            RobotEvent::parse(r#"PRGC:5018,0,"Saving to MKV file""#),
+            Some(RobotEvent::CurrentOperation(
+                "Saving to MKV file".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn parses_messages() {
+        assert_eq!(
+            RobotEvent::parse(
+                r#"MSG:5011,0,1,"Operation successfully completed","%1","Operation successfully completed""#
+            ),
+            Some(RobotEvent::Message {
+                code: 5011,
+                flags: 0,
+                message: "Operation successfully completed".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_disc_title_and_stream_info() {
+        assert_eq!(
+            RobotEvent::parse(r#"CINFO:2,0,"SHOW_S1_D1""#),
+            Some(RobotEvent::DiscInfo {
+                attribute: 2,
+                value: "SHOW_S1_D1".to_owned(),
+            })
+        );
+        assert_eq!(
+            RobotEvent::parse(r#"TINFO:3,9,0,"0:43:12""#),
+            Some(RobotEvent::TitleInfo {
+                title: 3,
+                attribute: 9,
+                value: "0:43:12".to_owned(),
+            })
+        );
+        assert_eq!(
+            RobotEvent::parse(r#"SINFO:3,1,3,0,"eng""#),
+            Some(RobotEvent::StreamInfo {
+                title: 3,
+                stream: 1,
+                attribute: 3,
+                value: "eng".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_and_malformed_lines() {
+        assert_eq!(RobotEvent::parse("DRV:0,2,999,1,\"BD-RE\""), None);
+        assert_eq!(RobotEvent::parse("PRGV:1024,lots,65536"), None);
+        assert_eq!(RobotEvent::parse("TINFO:3,9"), None);
+        assert_eq!(RobotEvent::parse("no colon here"), None);
+    }
+
+    /// A disc built from `TINFO` lines, one `(duration, chapters, segments)`
+    /// per title.
+    fn disc(titles: &[(&str, u32, &str)]) -> DiscInfo {
+        let mut disc = DiscInfo::default();
+
+        for (id, (duration, chapters, segments)) in titles.iter().enumerate() {
+            for line in [
+                format!(r#"TINFO:{},9,0,"{}""#, id, duration),
+                format!(r#"TINFO:{},8,0,"{}""#, id, chapters),
+                format!(r#"TINFO:{},26,0,"{}""#, id, segments),
+            ] {
+                disc.apply(&RobotEvent::parse(&line).unwrap());
+            }
+        }
+
+        disc
+    }
+
+    fn selection(strategy: SelectionStrategy) -> Selection {
+        Selection {
+            strategy,
+            min_length: Duration::from_secs(10 * 60),
+            episode_min_length: Duration::from_secs(20 * 60),
+            episode_max_length: Duration::from_secs(65 * 60),
+            titles: vec![1, 7, 0],
+        }
+    }
+
+    #[test]
+    fn reads_titles_from_robot_lines() {
+        let disc = disc(&[("1:52:10", 24, "1,2,3"), ("0:03:00", 1, "4")]);
+
+        assert_eq!(disc.titles.len(), 2);
+        assert_eq!(disc.titles[0].duration, Duration::from_secs(6730));
+        assert_eq!(disc.titles[0].chapter_count, 24);
+        assert_eq!(disc.titles[1].segment_map, "4");
+    }
+
+    #[test]
+    fn main_feature_is_the_longest_title() {
+        let disc = disc(&[
+            ("0:03:00", 1, "1"),
+            ("1:52:10", 24, "2,3,4"),
+            ("0:25:00", 6, "5"),
+        ]);
+
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::MainFeature)),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn main_feature_only_weighs_titles_within_the_tolerance() {
+        let disc = disc(&[
+            ("1:52:10", 12, "1,2,3"),
+            ("1:52:11", 24, "1,2,4"),
+            ("1:52:08", 40, "1,2,5"),
+        ]);
+
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::MainFeature)),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn main_feature_sees_through_obfuscated_playlists() {
+        let disc = disc(&[
+            ("2:01:33", 30, "3,1,2,4"),
+            ("2:01:33", 30, "4,3,2,1"),
+            ("2:01:33", 30, "1,2,3,4"),
+            ("2:01:33", 30, "2,1,4,3"),
+            ("2:01:33", 31, "1,2,3,4"),
+        ]);
+
+        // title 4 has more chapters, but plays the same segments as title 2
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::MainFeature)),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn main_feature_breaks_ties_on_chapters_then_lowest_id() {
+        let by_chapters = disc(&[("1:40:00", 16, "1-3"), ("1:40:00", 20, "4-6")]);
+        assert_eq!(
+            by_chapters.select_titles(&selection(SelectionStrategy::MainFeature)),
+            vec![1]
+        );
+
+        let by_id = disc(&[("1:40:00", 20, "1-3"), ("1:40:00", 20, "4-6")]);
+        assert_eq!(
+            by_id.select_titles(&selection(SelectionStrategy::MainFeature)),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn main_feature_of_an_empty_disc_is_nothing() {
+        assert!(
+            DiscInfo::default()
+                .select_titles(&selection(SelectionStrategy::MainFeature))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn episodes_fall_within_the_window() {
+        let disc = disc(&[
+            ("0:19:59", 4, "1"),
+            ("0:20:00", 4, "2"),
+            ("0:44:00", 6, "3"),
+            ("1:05:00", 8, "4"),
+            ("1:05:01", 8, "5"),
+            ("2:09:00", 24, "2,3,4"),
+        ]);
+
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::Episodes)),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn episodes_skip_repeated_segment_maps() {
+        let disc = disc(&[
+            ("0:44:00", 6, "1"),
+            ("0:44:00", 6, "1"),
+            ("0:45:00", 6, ""),
+            ("0:45:00", 6, ""),
+        ]);
+
+        // titles without a segment map can't be told apart, so all are kept
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::Episodes)),
+            vec![0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn all_above_keeps_titles_from_the_minimum_length() {
+        let disc = disc(&[
+            ("0:09:59", 1, "1"),
+            ("0:10:00", 2, "2"),
+            ("1:30:00", 20, "3"),
+        ]);
+
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::AllAbove)),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn titles_drops_ids_not_on_the_disc() {
+        let disc = disc(&[
+            ("1:30:00", 20, "1"),
+            ("0:30:00", 5, "2"),
+            ("0:05:00", 1, "3"),
+        ]);
+
+        assert_eq!(
+            disc.select_titles(&selection(SelectionStrategy::Titles)),
+            vec![1, 0]
+        );
+    }
 }
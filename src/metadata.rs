@@ -0,0 +1,344 @@
+use std::future::Future;
+use std::time::Duration;
+
+use failure::{Error, format_err};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Metadata, Provider};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Words disc labels commonly carry that aren't part of the title.
+const LABEL_NOISE: &[&str] = &[
+    "disc", "disk", "d1", "d2", "d3", "d4", "ws", "fs", "16x9", "4x3", "se", "ce", "dvd", "bluray",
+    "bd", "pal", "ntsc",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MediaKind {
+    Movie,
+    Show,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediaMatch {
+    pub kind: MediaKind,
+    pub id: u64,
+    pub title: String,
+    pub year: Option<u32>,
+}
+
+/// What we found out about a disc, kept in the rip's `meta.toml` so the
+/// encode can be named after it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum MediaLookup {
+    /// No provider is configured.
+    #[default]
+    Skipped,
+    Matched {
+        media: MediaMatch,
+    },
+    /// The lookup failed or wasn't confident, the encode keeps the disc's
+    /// own naming.
+    Unmatched {
+        reason: String,
+    },
+}
+
+pub trait MetadataProvider {
+    /// Candidate movies and shows for a search, best match first.
+    fn search(&self, query: &str) -> impl Future<Output = Result<Vec<MediaMatch>, Error>> + Send;
+}
+
+/// Talks to TMDB, or anything serving the same v3 search API.
+pub struct Tmdb {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResult {
+    id: u64,
+    media_type: Option<String>,
+    title: Option<String>,
+    name: Option<String>,
+    release_date: Option<String>,
+    first_air_date: Option<String>,
+}
+
+impl SearchResult {
+    fn into_match(self) -> Option<MediaMatch> {
+        let (kind, title, date) = match self.media_type.as_deref() {
+            Some("movie") => (MediaKind::Movie, self.title?, self.release_date),
+            Some("tv") => (MediaKind::Show, self.name?, self.first_air_date),
+            _ => return None,
+        };
+
+        Some(MediaMatch {
+            kind,
+            id: self.id,
+            title,
+            year: date.and_then(|date| date.get(..4)?.parse().ok()),
+        })
+    }
+}
+
+impl Tmdb {
+    pub fn new(config: &Metadata) -> Result<Tmdb, Error> {
+        Ok(Tmdb {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            base_url: config.base_url.trim_end_matches('/').to_owned(),
+            api_key: config.api_key.clone(),
+        })
+    }
+}
+
+impl MetadataProvider for Tmdb {
+    async fn search(&self, query: &str) -> Result<Vec<MediaMatch>, Error> {
+        let response = self
+            .client
+            .get(format!("{}/search/multi", self.base_url))
+            .query(&[("api_key", self.api_key.as_str()), ("query", query)])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format_err!(
+                "metadata search for {:?} returned {}",
+                query,
+                response.status()
+            ));
+        }
+
+        Ok(response
+            .json::<SearchResponse>()
+            .await?
+            .results
+            .into_iter()
+            .filter_map(SearchResult::into_match)
+            .collect())
+    }
+}
+
+/// Turns a disc label into something a search will find, e.g.
+/// "The Matrix Disc 1 Ws" into "The Matrix".
+fn search_query(label: &str) -> String {
+    let mut words = Vec::new();
+    let mut after_disc = false;
+
+    for word in label
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .filter(|word| !word.is_empty())
+    {
+        let lower = word.to_lowercase();
+
+        // the number in "Disc 1" is as much noise as the word before it
+        let disc_number = after_disc && word.chars().all(|c| c.is_ascii_digit());
+        after_disc = lower == "disc" || lower == "disk";
+
+        if !disc_number && !LABEL_NOISE.contains(&lower.as_str()) {
+            words.push(word);
+        }
+    }
+
+    words.join(" ")
+}
+
+/// Lowercase alphanumeric words without a leading article, so "MATRIX"
+/// and "The Matrix" compare equal.
+fn normalise(title: &str) -> String {
+    let words: Vec<String> = title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+
+    match words.split_first() {
+        Some((first, rest)) if ["the", "a", "an"].contains(&first.as_str()) && !rest.is_empty() => {
+            rest.join(" ")
+        }
+        _ => words.join(" "),
+    }
+}
+
+/// Matches a disc label against the provider, only accepting a result whose
+/// title is the label (or the only result there is).
+pub async fn identify(provider: &impl MetadataProvider, label: &str) -> MediaLookup {
+    let query = search_query(label);
+
+    let results = match provider.search(&query).await {
+        Ok(results) => results,
+        Err(e) => {
+            return MediaLookup::Unmatched {
+                reason: format!("lookup failed: {}", e),
+            };
+        }
+    };
+
+    let wanted = normalise(&query);
+
+    let found = match results.as_slice() {
+        [] => None,
+        [only] => Some(only.clone()),
+        results => results
+            .iter()
+            .find(|media| normalise(&media.title) == wanted)
+            .cloned(),
+    };
+
+    match found {
+        Some(media) => MediaLookup::Matched { media },
+        None => MediaLookup::Unmatched {
+            reason: match results.first() {
+                Some(top) => format!(
+                    "no confident match for {:?} (top result {:?})",
+                    query, top.title
+                ),
+                None => format!("nothing found for {:?}", query),
+            },
+        },
+    }
+}
+
+/// Looks a disc label up with the configured provider.
+pub async fn lookup(config: &Metadata, label: &str) -> MediaLookup {
+    let lookup = match config.provider {
+        Provider::None => return MediaLookup::Skipped,
+        Provider::Tmdb => match Tmdb::new(config) {
+            Ok(tmdb) => identify(&tmdb, label).await,
+            Err(e) => MediaLookup::Unmatched {
+                reason: e.to_string(),
+            },
+        },
+    };
+
+    match &lookup {
+        MediaLookup::Matched { media } => info!("{} is {:?} {:?}", label, media.kind, media.title),
+        MediaLookup::Unmatched { reason } => warn!("Unable to identify {}: {}", label, reason),
+        MediaLookup::Skipped => {}
+    }
+
+    lookup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every search with the same results, remembering the query.
+    struct Stub {
+        results: Result<Vec<MediaMatch>, String>,
+        query: std::sync::Mutex<Option<String>>,
+    }
+
+    impl Stub {
+        fn new(results: Result<Vec<MediaMatch>, String>) -> Stub {
+            Stub {
+                results,
+                query: Default::default(),
+            }
+        }
+
+        fn query(&self) -> Option<String> {
+            self.query.lock().unwrap().clone()
+        }
+    }
+
+    impl MetadataProvider for Stub {
+        async fn search(&self, query: &str) -> Result<Vec<MediaMatch>, Error> {
+            *self.query.lock().unwrap() = Some(query.to_owned());
+            self.results.clone().map_err(|e| format_err!("{}", e))
+        }
+    }
+
+    fn movie(id: u64, title: &str, year: u32) -> MediaMatch {
+        MediaMatch {
+            kind: MediaKind::Movie,
+            id,
+            title: title.to_owned(),
+            year: Some(year),
+        }
+    }
+
+    fn matched(lookup: MediaLookup) -> Option<u64> {
+        match lookup {
+            MediaLookup::Matched { media } => Some(media.id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn cleans_up_labels() {
+        assert_eq!(search_query("THE_MATRIX"), "THE MATRIX");
+        assert_eq!(search_query("The Matrix Disc 1 Ws"), "The Matrix");
+        assert_eq!(search_query("ALIEN_D1_16x9"), "ALIEN");
+        assert_eq!(search_query("HEAT__PAL_DVD"), "HEAT");
+        assert_eq!(search_query("  Blade   Runner  NTSC "), "Blade Runner");
+    }
+
+    #[test]
+    fn normalises_titles() {
+        assert_eq!(normalise("The Matrix"), "matrix");
+        assert_eq!(normalise("MATRIX"), "matrix");
+        assert_eq!(normalise("Alien: Resurrection"), "alien resurrection");
+        assert_eq!(normalise("A Quiet Place"), "quiet place");
+        assert_eq!(normalise("The"), "the");
+    }
+
+    #[tokio::test]
+    async fn picks_the_result_matching_the_label() {
+        let stub = Stub::new(Ok(vec![
+            movie(1, "The Matrix Reloaded", 2003),
+            movie(2, "The Matrix", 1999),
+            movie(3, "Matrix", 1993),
+        ]));
+
+        assert_eq!(matched(identify(&stub, "THE_MATRIX_WS").await), Some(2));
+        assert_eq!(stub.query().as_deref(), Some("THE MATRIX"));
+    }
+
+    #[tokio::test]
+    async fn accepts_a_single_result() {
+        let stub = Stub::new(Ok(vec![movie(7, "Heat", 1995)]));
+
+        assert_eq!(matched(identify(&stub, "HEAT_SE").await), Some(7));
+    }
+
+    #[tokio::test]
+    async fn ambiguous_results_are_unmatched() {
+        let stub = Stub::new(Ok(vec![movie(1, "Alien", 1979), movie(2, "Aliens", 1986)]));
+
+        match identify(&stub, "ALIENS_VS_PREDATOR").await {
+            MediaLookup::Unmatched { reason } => assert!(reason.contains("Alien"), "{}", reason),
+            lookup => panic!("expected no match, got {:?}", lookup),
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_and_failed_searches_are_unmatched() {
+        let empty = Stub::new(Ok(Vec::new()));
+        assert!(matches!(
+            identify(&empty, "NOTHING").await,
+            MediaLookup::Unmatched { .. }
+        ));
+
+        let failed = Stub::new(Err("connection refused".to_owned()));
+        match identify(&failed, "ALIEN").await {
+            MediaLookup::Unmatched { reason } => {
+                assert_eq!(reason, "lookup failed: connection refused")
+            }
+            lookup => panic!("expected no match, got {:?}", lookup),
+        }
+    }
+}
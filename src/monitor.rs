@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use log::{debug, info, warn};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::{self, JoinHandle},
+    time::sleep,
+};
+
+use crate::cdrom::{self, DriveState};
+
+/// How long a drive gets to spin a disc up before we give up on it.
+const SPIN_UP_TIMEOUT: Duration = Duration::from_secs(60);
+const SPIN_UP_POLL: Duration = Duration::from_secs(1);
+
+pub type MediaEvents = HashMap<String, UnboundedReceiver<MediaEvent>>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MediaEvent {
+    Inserted,
+    Ejected,
+}
+
+/// Waits for discs to be inserted into a single drive, either by listening
+/// to udev events or by polling the drive's tray state.
+pub enum DiscWatcher {
+    Udev(UnboundedReceiver<MediaEvent>, Duration),
+    Poll(Duration),
+}
+
+impl DiscWatcher {
+    pub async fn wait_for_insert(&mut self, device: &str) {
+        match self {
+            DiscWatcher::Udev(rx, sleep_time) => loop {
+                match rx.recv().await {
+                    Some(MediaEvent::Inserted) => {
+                        info!("disc inserted into {}", device);
+                        return;
+                    }
+                    Some(MediaEvent::Ejected) => {
+                        info!("disc ejected from {}", device);
+                    }
+                    None => {
+                        warn!("udev monitor stopped, falling back to polling {}", device);
+                        *self = DiscWatcher::Poll(*sleep_time);
+                        return;
+                    }
+                }
+            },
+            DiscWatcher::Poll(sleep_time) => {
+                // wait for the disc that was in the drive to come out before
+                // watching for the next one to go in
+                let mut emptied = false;
+
+                loop {
+                    sleep(*sleep_time).await;
+
+                    match drive_state(device).await {
+                        Ok(DriveState::Open | DriveState::Empty) => emptied = true,
+                        Ok(DriveState::NotReady | DriveState::DiscOk) if emptied => {
+                            info!("disc inserted into {}", device);
+                            return;
+                        }
+                        Ok(_) => {}
+                        // without the tray state all we can do is check again
+                        Err(_) => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads a drive's tray state without blocking the runtime, since opening a
+/// drive that's spinning a disc up can take a while.
+pub async fn drive_state(device: &str) -> std::io::Result<DriveState> {
+    let device = device.to_owned();
+    task::spawn_blocking(move || cdrom::drive_state(&device))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Whether there's a disc ready to be read, waiting for the drive to finish
+/// spinning it up if it's only just gone in.
+pub async fn disc_ready(device: &str) -> bool {
+    let started = Instant::now();
+
+    loop {
+        match drive_state(device).await {
+            Ok(DriveState::DiscOk) => return true,
+            Ok(DriveState::NotReady) if started.elapsed() < SPIN_UP_TIMEOUT => {
+                debug!("waiting for {} to spin up", device);
+                sleep(SPIN_UP_POLL).await;
+            }
+            Ok(DriveState::NotReady) => {
+                warn!(
+                    "{} didn't become ready within {:?}",
+                    device, SPIN_UP_TIMEOUT
+                );
+                return false;
+            }
+            Ok(_) => return false,
+            Err(e) => {
+                // not every device answers the CD-ROM ioctls, so fall back to
+                // whether it can be opened at all
+                debug!("unable to read the tray state of {}: {}", device, e);
+                return tokio::fs::File::open(device).await.is_ok();
+            }
+        }
+    }
+}
+
+/// Listens to the udev `block` subsystem and forwards media change events
+/// for the given devices. Fails if the udev netlink socket is unavailable,
+/// e.g. when running inside a container that doesn't expose it.
+pub fn spawn_udev_monitor(
+    devices: &[String],
+) -> Result<(MediaEvents, JoinHandle<Result<(), Error>>), Error> {
+    let socket = udev::MonitorBuilder::new()?
+        .match_subsystem("block")?
+        .listen()?;
+    let socket = AsyncFd::new(socket)?;
+
+    let mut senders: HashMap<PathBuf, UnboundedSender<MediaEvent>> = HashMap::new();
+    let mut receivers = HashMap::new();
+
+    for device in devices {
+        let (tx, rx) = unbounded_channel();
+        // resolve symlinks such as /dev/cdrom so they match the devnode udev reports
+        let path = std::fs::canonicalize(device).unwrap_or_else(|_| PathBuf::from(device));
+        senders.insert(path, tx);
+        receivers.insert(device.clone(), rx);
+    }
+
+    let handle = tokio::spawn(async move {
+        loop {
+            let mut guard = socket.readable().await?;
+
+            for event in guard.get_inner().iter() {
+                if event.event_type() != udev::EventType::Change {
+                    continue;
+                }
+
+                let Some(tx) = event.devnode().and_then(|node| senders.get(node)) else {
+                    continue;
+                };
+
+                let Some(media_event) = media_event(&event) else {
+                    continue;
+                };
+
+                debug!("udev {:?} event for {:?}", media_event, event.devnode());
+
+                // a closed receiver only means that drive's rip loop has exited
+                let _ = tx.send(media_event);
+            }
+
+            guard.clear_ready();
+
+            if senders.values().all(|tx| tx.is_closed()) {
+                info!("exiting udev monitor");
+                return Ok(());
+            }
+        }
+    });
+
+    Ok((receivers, handle))
+}
+
+fn media_event(event: &udev::Event) -> Option<MediaEvent> {
+    let is_set = |name: &str| event.property_value(name).is_some_and(|v| v == "1");
+
+    if is_set("DISK_EJECT_REQUEST") {
+        return Some(MediaEvent::Ejected);
+    }
+
+    if is_set("DISK_MEDIA_CHANGE") {
+        if is_set("ID_CDROM_MEDIA") {
+            return Some(MediaEvent::Inserted);
+        }
+
+        return Some(MediaEvent::Ejected);
+    }
+
+    None
+}
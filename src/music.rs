@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use failure::{Error, format_err};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock, task};
+
+use crate::cdrom::{self, Toc};
+use crate::command::{fill, run};
+use crate::config::Music;
+use crate::disc::Disc;
+use crate::makemkv::RipStatuses;
+use crate::musicbrainz::{self, Candidate};
+use crate::naming::sanitise;
+
+const UNKNOWN_ARTIST: &str = "Unknown Artist";
+
+/// Albums ripped without a confident metadata match, keyed by disc ID.
+pub type Reviews = Arc<RwLock<HashMap<String, Review>>>;
+
+/// An album that was ripped with placeholder names because the lookup failed
+/// or couldn't pick between several releases.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Review {
+    pub disc_id: String,
+    pub freedb_id: String,
+    pub title: String,
+    pub album_dir: PathBuf,
+    pub reason: String,
+    pub candidates: Vec<Candidate>,
+    pub ripped_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Album {
+    pub artist: String,
+    pub title: String,
+    pub release_id: Option<String>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub number: u8,
+    pub title: String,
+    pub artist: String,
+}
+
+impl Album {
+    /// Placeholder metadata for a disc we know nothing about beyond its TOC.
+    fn unknown(disc: &Disc, toc: &Toc) -> Album {
+        Album {
+            artist: UNKNOWN_ARTIST.to_owned(),
+            title: disc.title(),
+            release_id: None,
+            tracks: toc
+                .audio_tracks()
+                .map(|t| Track {
+                    number: t.number,
+                    title: format!("Track {:02}", t.number),
+                    artist: UNKNOWN_ARTIST.to_owned(),
+                })
+                .collect(),
+        }
+    }
+
+    fn directory(&self, output: &Path) -> PathBuf {
+        output
+            .join(sanitise(&self.artist))
+            .join(sanitise(&self.title))
+    }
+}
+
+impl Track {
+    fn file_name(&self, extension: &str) -> String {
+        sanitise(&format!(
+            "{:02} - {}.{}",
+            self.number, self.title, extension
+        ))
+    }
+}
+
+/// Picks up the reviews left next to previously ripped albums, so they're
+/// still flagged after a restart.
+pub async fn load_reviews(output: &Path) -> Result<Reviews, Error> {
+    let mut reviews = HashMap::new();
+
+    if !output.is_dir() {
+        return Ok(Arc::new(RwLock::new(reviews)));
+    }
+
+    let mut artists = fs::read_dir(output).await?;
+
+    while let Some(artist) = artists.next_entry().await? {
+        if !artist.path().is_dir() {
+            continue;
+        }
+
+        let mut albums = fs::read_dir(artist.path()).await?;
+
+        while let Some(album) = albums.next_entry().await? {
+            let path = album.path().join("review.json");
+
+            if !path.is_file() {
+                continue;
+            }
+
+            match serde_json::from_slice::<Review>(&fs::read(&path).await?) {
+                Ok(review) => {
+                    reviews.insert(review.disc_id.clone(), review);
+                }
+                Err(e) => warn!("Ignoring unreadable review {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    Ok(Arc::new(RwLock::new(reviews)))
+}
+
+/// Clears a review once the album has been sorted out by hand.
+pub async fn dismiss_review(reviews: &Reviews, disc_id: &str) -> Result<bool, Error> {
+    let Some(review) = reviews.write().await.remove(disc_id) else {
+        return Ok(false);
+    };
+
+    let path = review.album_dir.join("review.json");
+
+    if path.is_file() {
+        fs::remove_file(path).await?;
+    }
+
+    Ok(true)
+}
+
+/// Looks the disc up on MusicBrainz, falling back to placeholder names (and
+/// the reason why) when there isn't exactly one matching release.
+async fn identify(
+    config: &Music,
+    disc: &Disc,
+    toc: &Toc,
+    disc_id: &str,
+) -> (Album, Option<(String, Vec<Candidate>)>) {
+    let releases = match musicbrainz::lookup(&config.musicbrainz_url, disc_id).await {
+        Ok(releases) => releases,
+        Err(e) => {
+            let reason = format!("MusicBrainz lookup failed: {}", e);
+            return (Album::unknown(disc, toc), Some((reason, Vec::new())));
+        }
+    };
+
+    let candidates = releases.iter().map(|r| r.candidate()).collect();
+
+    let reason = match releases.as_slice() {
+        [] => "no matching release on MusicBrainz".to_owned(),
+        [release] => match release.album(disc_id, toc) {
+            Some(album) => return (album, None),
+            None => format!("track listing of {} doesn't match the disc", release.id),
+        },
+        releases => format!("{} candidate releases on MusicBrainz", releases.len()),
+    };
+
+    (Album::unknown(disc, toc), Some((reason, candidates)))
+}
+
+/// Rips every audio track on the disc, encodes them to FLAC (and the
+/// configured lossy format, if any) and writes a CUE sheet and rip log
+/// alongside them in `output/Artist/Album`.
+pub async fn rip(
+    config: &Music,
+    disc: &Disc,
+    raw: &Path,
+    output: &Path,
+    statuses: &RipStatuses,
+    reviews: &Reviews,
+) -> Result<PathBuf, Error> {
+    let device = disc.name.clone();
+    let toc = task::spawn_blocking(move || cdrom::read_toc(&device)).await??;
+
+    if toc.audio_tracks().next().is_none() {
+        return Err(format_err!("no audio tracks found on {}", disc.name));
+    }
+
+    let disc_id = musicbrainz::disc_id(&toc);
+    let freedb_id = musicbrainz::freedb_id(&toc);
+
+    if let Some(status) = statuses.write().await.get_mut(&disc.name) {
+        status.operation = Some("Looking up disc".to_owned());
+    }
+
+    let (album, unmatched) = identify(config, disc, &toc, &disc_id).await;
+
+    let work_dir = raw.join(disc.path_friendly_title());
+    let album_dir = album.directory(output);
+
+    fs::create_dir_all(&work_dir).await?;
+    fs::create_dir_all(&album_dir).await?;
+
+    let log_path = album_dir.join(format!("{}.log", sanitise(&album.title)));
+    let mut log = fs::File::create(&log_path).await?;
+
+    log.write_all(toc_report(&toc).as_bytes()).await?;
+    log.write_all(
+        format!(
+            "MusicBrainz disc ID: {}\nFreeDB ID: {}\n\n",
+            disc_id, freedb_id
+        )
+        .as_bytes(),
+    )
+    .await?;
+
+    if let Some((reason, candidates)) = unmatched {
+        warn!("{} needs review: {}", disc.title(), reason);
+
+        let review = Review {
+            disc_id: disc_id.clone(),
+            freedb_id: freedb_id.clone(),
+            title: disc.title(),
+            album_dir: album_dir.clone(),
+            reason,
+            candidates,
+            ripped_at: Utc::now(),
+        };
+
+        fs::write(
+            album_dir.join("review.json"),
+            serde_json::to_string_pretty(&review)?,
+        )
+        .await?;
+
+        reviews.write().await.insert(disc_id.clone(), review);
+    }
+
+    info!(
+        "Ripping {} tracks from {} into {}",
+        album.tracks.len(),
+        disc.name,
+        album_dir.display()
+    );
+
+    for (index, track) in album.tracks.iter().enumerate() {
+        if let Some(status) = statuses.write().await.get_mut(&disc.name) {
+            status.title_index = index + 1;
+            status.title_count = album.tracks.len();
+            status.operation = Some("Ripping track".to_owned());
+            status.total_progress = index as f32 / album.tracks.len() as f32;
+        }
+
+        let wav = work_dir.join(format!("track{:02}.wav", track.number));
+        let number = track.number.to_string();
+
+        let ripper = fill(
+            &config.ripper,
+            &[
+                ("device", &disc.name),
+                ("track", &number),
+                ("output", &wav.to_string_lossy()),
+            ],
+        );
+
+        run(&ripper, &mut log).await?;
+
+        if let Some(status) = statuses.write().await.get_mut(&disc.name) {
+            status.operation = Some("Encoding track".to_owned());
+        }
+
+        let flac = album_dir.join(track.file_name("flac"));
+
+        let mut encoder = vec!["flac".to_owned()];
+        encoder.extend(config.flac_args.iter().cloned());
+        encoder.extend([
+            format!("--tag=ARTIST={}", track.artist),
+            format!("--tag=ALBUMARTIST={}", album.artist),
+            format!("--tag=ALBUM={}", album.title),
+            format!("--tag=TITLE={}", track.title),
+            format!("--tag=TRACKNUMBER={}", track.number),
+            format!("--tag=TRACKTOTAL={}", album.tracks.len()),
+            format!("--tag=MUSICBRAINZ_DISCID={}", disc_id),
+        ]);
+
+        if let Some(release_id) = &album.release_id {
+            encoder.push(format!("--tag=MUSICBRAINZ_ALBUMID={}", release_id));
+        }
+
+        encoder.extend([
+            "-f".to_owned(),
+            "-o".to_owned(),
+            flac.to_string_lossy().into_owned(),
+            wav.to_string_lossy().into_owned(),
+        ]);
+
+        run(&encoder, &mut log).await?;
+
+        if let Some(lossy) = &config.lossy {
+            let target = album_dir.join(track.file_name(&lossy.extension));
+
+            let command = fill(
+                &lossy.command,
+                &[
+                    ("input", &wav.to_string_lossy()),
+                    ("output", &target.to_string_lossy()),
+                    ("artist", &track.artist),
+                    ("album", &album.title),
+                    ("title", &track.title),
+                    ("track", &number),
+                ],
+            );
+
+            run(&command, &mut log).await?;
+        }
+
+        fs::remove_file(&wav).await?;
+    }
+
+    fs::write(
+        album_dir.join(format!("{}.cue", sanitise(&album.title))),
+        cue_sheet(&album, &freedb_id),
+    )
+    .await?;
+
+    fs::remove_dir_all(&work_dir).await?;
+
+    Ok(album_dir)
+}
+
+/// The disc's table of contents, as recorded at the top of the rip log.
+fn toc_report(toc: &Toc) -> String {
+    let mut report = String::from("Track | Start sector | Type\n");
+
+    for track in &toc.tracks {
+        report.push_str(&format!(
+            "{:>5} | {:>12} | {}\n",
+            track.number,
+            track.start,
+            if track.audio { "audio" } else { "data" }
+        ));
+    }
+
+    report.push_str(&format!("Lead-out at sector {}\n\n", toc.leadout));
+
+    report
+}
+
+fn cue_sheet(album: &Album, freedb_id: &str) -> String {
+    let quote = |s: &str| s.replace('"', "'");
+
+    let mut cue = format!(
+        "REM DISCID {}\nPERFORMER \"{}\"\nTITLE \"{}\"\n",
+        freedb_id,
+        quote(&album.artist),
+        quote(&album.title)
+    );
+
+    for track in &album.tracks {
+        cue.push_str(&format!(
+            "FILE \"{}\" WAVE\n  TRACK {:02} AUDIO\n    TITLE \"{}\"\n    PERFORMER \"{}\"\n    INDEX 01 00:00:00\n",
+            quote(&track.file_name("flac")),
+            track.number,
+            quote(&track.title),
+            quote(&track.artist)
+        ));
+    }
+
+    cue
+}
@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use failure::{Error, format_err};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::cdrom::Toc;
+use crate::music::{Album, Track};
+
+/// The two second pregap before the first track, in sectors.
+const PREGAP: u32 = 150;
+/// Sectors between the audio session and a trailing data session on
+/// enhanced CDs.
+const SESSION_GAP: u32 = 11400;
+const SECTORS_PER_SECOND: u32 = 75;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The disc ID MusicBrainz uses to find releases from a CD's TOC, see
+/// https://musicbrainz.org/doc/Disc_ID_Calculation
+pub fn disc_id(toc: &Toc) -> String {
+    let audio: Vec<_> = toc.audio_tracks().collect();
+    let first = audio.first().map(|t| t.number).unwrap_or(1);
+    let last = audio.last().map(|t| t.number).unwrap_or(0);
+
+    // on enhanced CDs the audio session ends well before the data track
+    let leadout = toc
+        .tracks
+        .iter()
+        .find(|t| !t.audio && t.number > last)
+        .map(|data| data.start.saturating_sub(SESSION_GAP))
+        .unwrap_or(toc.leadout);
+
+    let mut offsets = [0; 100];
+    offsets[0] = leadout + PREGAP;
+    for track in &audio {
+        offsets[track.number as usize] = track.start + PREGAP;
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{:02X}{:02X}", first, last));
+    for offset in offsets {
+        hasher.update(format!("{:08X}", offset));
+    }
+
+    STANDARD
+        .encode(hasher.finalize())
+        .chars()
+        .map(|c| match c {
+            '+' => '.',
+            '/' => '_',
+            '=' => '-',
+            c => c,
+        })
+        .collect()
+}
+
+/// The older FreeDB/CDDB disc ID, still used in CUE sheets and by other
+/// taggers.
+pub fn freedb_id(toc: &Toc) -> String {
+    let seconds = |sector: u32| (sector + PREGAP) / SECTORS_PER_SECOND;
+    let digit_sum = |mut n: u32| {
+        let mut sum = 0;
+        while n > 0 {
+            sum += n % 10;
+            n /= 10;
+        }
+        sum
+    };
+
+    let checksum: u32 = toc.tracks.iter().map(|t| digit_sum(seconds(t.start))).sum();
+    let start = toc.tracks.first().map(|t| seconds(t.start)).unwrap_or(0);
+    let length = seconds(toc.leadout).saturating_sub(start);
+
+    format!(
+        "{:08x}",
+        ((checksum % 0xff) << 24) | (length << 8) | toc.tracks.len() as u32
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscResponse {
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    pub id: String,
+    pub title: String,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+    pub date: Option<String>,
+    pub country: Option<String>,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ArtistCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Medium {
+    #[serde(default)]
+    discs: Vec<MediumDisc>,
+    #[serde(default)]
+    tracks: Vec<MediumTrack>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct MediumDisc {
+    id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct MediumTrack {
+    title: String,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+}
+
+/// A release offered for a disc, as shown to the user when picking between
+/// several.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Candidate {
+    pub id: String,
+    pub artist: String,
+    pub title: String,
+    pub date: Option<String>,
+    pub country: Option<String>,
+}
+
+fn credit(credits: &[ArtistCredit]) -> String {
+    credits
+        .iter()
+        .map(|c| format!("{}{}", c.name, c.joinphrase))
+        .collect()
+}
+
+impl Release {
+    pub fn artist(&self) -> String {
+        credit(&self.artist_credit)
+    }
+
+    pub fn candidate(&self) -> Candidate {
+        Candidate {
+            id: self.id.clone(),
+            artist: self.artist(),
+            title: self.title.clone(),
+            date: self.date.clone(),
+            country: self.country.clone(),
+        }
+    }
+
+    /// Names the disc's audio tracks from the medium of this release the
+    /// disc belongs to, or `None` if the track listing doesn't line up.
+    pub fn album(&self, disc_id: &str, toc: &Toc) -> Option<Album> {
+        let medium = self
+            .media
+            .iter()
+            .find(|m| m.discs.iter().any(|d| d.id == disc_id))?;
+
+        let audio: Vec<_> = toc.audio_tracks().collect();
+
+        if medium.tracks.len() != audio.len() {
+            return None;
+        }
+
+        let artist = self.artist();
+
+        Some(Album {
+            artist: artist.clone(),
+            title: self.title.clone(),
+            release_id: Some(self.id.clone()),
+            tracks: audio
+                .iter()
+                .zip(&medium.tracks)
+                .map(|(toc_track, track)| Track {
+                    number: toc_track.number,
+                    title: track.title.clone(),
+                    artist: match credit(&track.artist_credit) {
+                        credit if credit.is_empty() => artist.clone(),
+                        credit => credit,
+                    },
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Finds the releases a disc ID belongs to. A disc ID MusicBrainz doesn't
+/// know about is an empty list rather than an error.
+pub async fn lookup(base_url: &str, disc_id: &str) -> Result<Vec<Release>, Error> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!(
+            "torn/",
+            env!("CARGO_PKG_VERSION"),
+            " ( https://github.com/simonhdickson/torn )"
+        ))
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+
+    let response = client
+        .get(format!(
+            "{}/ws/2/discid/{}",
+            base_url.trim_end_matches('/'),
+            disc_id
+        ))
+        .query(&[("inc", "artist-credits recordings"), ("fmt", "json")])
+        .send()
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+
+    if !response.status().is_success() {
+        return Err(format_err!(
+            "MusicBrainz returned {} for disc {}",
+            response.status(),
+            disc_id
+        ));
+    }
+
+    Ok(response.json::<DiscResponse>().await?.releases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdrom::TocTrack;
+
+    /// A TOC from its lead-out and track offsets as MusicBrainz writes them,
+    /// i.e. including the 150 sector pregap.
+    fn toc(leadout: u32, offsets: &[u32]) -> Toc {
+        Toc {
+            tracks: offsets
+                .iter()
+                .zip(1..)
+                .map(|(offset, number)| TocTrack {
+                    number,
+                    start: offset - PREGAP,
+                    audio: true,
+                })
+                .collect(),
+            leadout: leadout - PREGAP,
+        }
+    }
+
+    /// The example disc from libdiscid's tests.
+    fn example() -> Toc {
+        toc(
+            206535,
+            &[
+                150, 18901, 39738, 59557, 79152, 100126, 124833, 147278, 166336, 182560,
+            ],
+        )
+    }
+
+    #[test]
+    fn disc_id_matches_known_answer() {
+        assert_eq!(disc_id(&example()), "Wn8eRBtfLDfM0qjYPdxrz.Zjs_U-");
+    }
+
+    #[test]
+    fn freedb_id_matches_known_answer() {
+        assert_eq!(freedb_id(&example()), "830abf0a");
+    }
+
+    #[test]
+    fn enhanced_cd_ends_before_the_data_session() {
+        let mut enhanced = example();
+        enhanced.tracks.push(TocTrack {
+            number: 11,
+            start: 206535 - PREGAP + SESSION_GAP,
+            audio: false,
+        });
+        enhanced.leadout = 250000;
+
+        assert_eq!(disc_id(&enhanced), disc_id(&example()));
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+/// Makes a name safe to use as a single path component.
+pub fn sanitise(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let name = name.trim().trim_start_matches('.');
+
+    if name.is_empty() {
+        "_".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Fills in a `/`-separated output template such as
+/// `Movies/{title} ({year})/{title} ({year}).{extension}`. Values are
+/// sanitised first so they can't add directories of their own, and
+/// placeholders without a value are left empty.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> PathBuf {
+    template
+        .split('/')
+        .map(|component| {
+            let mut filled = String::new();
+            let mut rest = component;
+
+            while let Some(start) = rest.find('{') {
+                let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                    break;
+                };
+
+                filled.push_str(&rest[..start]);
+
+                let name = &rest[start + 1..end];
+                if let Some((_, value)) = vars.iter().find(|(var, _)| *var == name)
+                    && !value.is_empty()
+                {
+                    filled.push_str(&sanitise(value));
+                }
+
+                rest = &rest[end + 1..];
+            }
+
+            filled.push_str(rest);
+
+            // drop the brackets an empty value leaves behind, e.g. a missing year
+            sanitise(&filled.replace(" ()", "").replace("()", ""))
+        })
+        .collect()
+}
+
+/// Adds a " (2)", " (3)", ... suffix to the file name until `taken` no
+/// longer holds for it.
+pub fn unique_path(path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    if !taken(path) {
+        return path.to_path_buf();
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (2..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !taken(candidate))
+        .expect("ran out of suffixes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn renders_templates() {
+        assert_eq!(
+            render(
+                "Movies/{title} ({year})/{title} ({year}).{extension}",
+                &[("title", "Alien"), ("year", "1979"), ("extension", "mkv")]
+            ),
+            PathBuf::from("Movies/Alien (1979)/Alien (1979).mkv")
+        );
+    }
+
+    #[test]
+    fn drops_brackets_around_missing_values() {
+        assert_eq!(
+            render(
+                "Movies/{title} ({year})/{title} ({year}).{extension}",
+                &[("title", "Alien"), ("year", ""), ("extension", "mkv")]
+            ),
+            PathBuf::from("Movies/Alien/Alien.mkv")
+        );
+        assert_eq!(
+            render("{title} {unknown}.mkv", &[("title", "Alien")]),
+            PathBuf::from("Alien .mkv")
+        );
+    }
+
+    #[test]
+    fn values_cant_add_directories() {
+        assert_eq!(
+            render("Movies/{title}.mkv", &[("title", "../../etc/passwd")]),
+            PathBuf::from("Movies/_.._etc_passwd.mkv")
+        );
+        assert_eq!(
+            render(
+                "TV/{show}/{title}",
+                &[("show", ".."), ("title", "AC/DC: Live")]
+            ),
+            PathBuf::from("TV/_/AC_DC_ Live")
+        );
+    }
+
+    #[test]
+    fn leaves_unclosed_braces_alone() {
+        assert_eq!(
+            render("{title} {oops.mkv", &[("title", "Alien")]),
+            PathBuf::from("Alien {oops.mkv")
+        );
+    }
+
+    #[test]
+    fn suffixes_taken_paths() {
+        let taken: HashSet<PathBuf> = ["out/Alien.mkv", "out/Alien (2).mkv", "out/notes"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let taken = |path: &Path| taken.contains(path);
+
+        assert_eq!(
+            unique_path(Path::new("out/Heat.mkv"), taken),
+            PathBuf::from("out/Heat.mkv")
+        );
+        assert_eq!(
+            unique_path(Path::new("out/Alien.mkv"), taken),
+            PathBuf::from("out/Alien (3).mkv")
+        );
+        assert_eq!(
+            unique_path(Path::new("out/notes"), taken),
+            PathBuf::from("out/notes (2)")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// Job ids waiting to be encoded, highest priority first and oldest first
+/// within the same priority.
+#[derive(Default)]
+pub struct JobQueue {
+    entries: Mutex<Entries>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Entries {
+    heap: BinaryHeap<Entry>,
+    sequence: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    priority: i32,
+    sequence: u64,
+    id: String,
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl JobQueue {
+    pub fn push(&self, id: String, priority: i32) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let sequence = entries.sequence;
+            entries.sequence += 1;
+            entries.heap.push(Entry {
+                priority,
+                sequence,
+                id,
+            });
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Waits until there is a job to hand out.
+    pub async fn pop(&self) -> String {
+        loop {
+            if let Some(entry) = self.entries.lock().unwrap().heap.pop() {
+                return entry.id;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.heap.len();
+        entries.heap.retain(|entry| entry.id != id);
+        entries.heap.len() != len
+    }
+
+    /// Changes the priority of a waiting job, keeping its place among jobs of
+    /// the same priority.
+    pub fn reprioritise(&self, id: &str, priority: i32) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let mut found = false;
+
+        let heap = std::mem::take(&mut entries.heap)
+            .into_iter()
+            .map(|mut entry| {
+                if entry.id == id {
+                    entry.priority = priority;
+                    found = true;
+                }
+                entry
+            })
+            .collect();
+
+        entries.heap = heap;
+        found
+    }
+}
@@ -0,0 +1,582 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use failure::Error;
+use heck::ToTitleCase;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::RwLock};
+
+use crate::config::{Selection, SelectionStrategy};
+use crate::disc::{Disc, DiscMetadata};
+use crate::makemkv::DiscInfo;
+use crate::store;
+
+/// How many similar-length titles make an unlabelled disc look like a
+/// series disc.
+const MIN_CLUSTER_SIZE: usize = 3;
+/// The longest title in a cluster may be at most this much longer than the
+/// shortest.
+const CLUSTER_SPREAD: f64 = 1.25;
+/// A title longer than any episode is taken as a "play all" of the cluster
+/// if it runs within this fraction of the episodes' total length.
+const PLAY_ALL_TOLERANCE: f64 = 0.05;
+
+/// A disc that looks like part of a TV series box set.
+#[derive(Clone, Debug)]
+pub struct SeriesDisc {
+    pub show: String,
+    pub season: u32,
+    /// Position of the disc within the season, `None` when the label didn't
+    /// say and it's assumed to follow the last disc we saw.
+    pub disc: Option<u32>,
+}
+
+/// Which episode each ripped title is, kept in the rip's `meta.toml`.
+/// Encoding waits until the mapping has been confirmed in the web UI.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EpisodeMapping {
+    pub show: String,
+    pub season: u32,
+    pub disc: u32,
+    pub episodes: Vec<EpisodeFile>,
+    pub confirmed: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EpisodeFile {
+    pub file: String,
+    pub title_id: usize,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    /// `None` leaves the file out of the encode.
+    pub episode: Option<u32>,
+}
+
+impl EpisodeMapping {
+    /// The episode a ripped file was mapped to, if it's to be encoded.
+    pub fn episode(&self, file: &str) -> Option<u32> {
+        self.episodes
+            .iter()
+            .find(|e| e.file == file)
+            .and_then(|e| e.episode)
+    }
+}
+
+/// An edited mapping, as posted from the web UI.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ConfirmRequest {
+    pub show: Option<String>,
+    pub season: Option<u32>,
+    pub episodes: Vec<EpisodeAssignment>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EpisodeAssignment {
+    pub file: String,
+    pub episode: Option<u32>,
+}
+
+/// A rip waiting on its episode mapping to be confirmed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingMapping {
+    pub id: String,
+    pub folder: PathBuf,
+    pub mapping: EpisodeMapping,
+}
+
+#[derive(Debug)]
+pub enum SeriesError {
+    NotFound(String),
+    Invalid(String),
+    Failed(Error),
+}
+
+impl fmt::Display for SeriesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeriesError::NotFound(id) => {
+                write!(f, "no episode mapping awaiting confirmation for {}", id)
+            }
+            SeriesError::Invalid(reason) => write!(f, "invalid episode mapping: {}", reason),
+            SeriesError::Failed(e) => write!(f, "unable to save episode mapping: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SeriesError {}
+
+/// Episodes already handed out per show and season, keyed by disc number,
+/// so the next disc of a season carries on where the last one stopped.
+type Seasons = HashMap<String, BTreeMap<u32, Vec<u32>>>;
+
+#[derive(Clone)]
+pub struct SeriesStore {
+    path: PathBuf,
+    seasons: Arc<RwLock<Seasons>>,
+    pending: Arc<RwLock<HashMap<String, PendingMapping>>>,
+}
+
+fn season_key(show: &str, season: u32) -> String {
+    format!("{}/{}", show, season)
+}
+
+impl SeriesStore {
+    /// Loads the season history from the state directory and picks up any
+    /// rips in `raw` still waiting on confirmation.
+    pub async fn load(state_dir: &Path, raw: &Path) -> Result<SeriesStore, Error> {
+        let path = state_dir.join("series.json");
+
+        let seasons = if path.is_file() {
+            serde_json::from_slice(&fs::read(&path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        let mut pending = HashMap::new();
+
+        if raw.is_dir() {
+            let mut folders = fs::read_dir(raw).await?;
+
+            while let Some(entry) = folders.next_entry().await? {
+                let Ok(meta) = fs::read(entry.path().join("meta.toml")).await else {
+                    continue;
+                };
+
+                match toml::from_slice::<DiscMetadata>(&meta) {
+                    Ok(DiscMetadata {
+                        series: Some(mapping),
+                        ..
+                    }) if !mapping.confirmed => {
+                        let id = entry.file_name().to_string_lossy().into_owned();
+                        pending.insert(
+                            id.clone(),
+                            PendingMapping {
+                                id,
+                                folder: entry.path(),
+                                mapping,
+                            },
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Ignoring unreadable {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+
+        Ok(SeriesStore {
+            path,
+            seasons: Arc::new(RwLock::new(seasons)),
+            pending: Arc::new(RwLock::new(pending)),
+        })
+    }
+
+    async fn save(&self, seasons: &Seasons) -> Result<(), Error> {
+        store::write_json_atomic(&self.path, seasons).await
+    }
+
+    /// Numbers the titles being ripped from a series disc, carrying on from
+    /// the earlier discs of the same season. Nothing is remembered until the
+    /// rip succeeds and the mapping is recorded.
+    pub async fn propose(
+        &self,
+        series: &SeriesDisc,
+        disc_info: &DiscInfo,
+        titles: &[usize],
+    ) -> EpisodeMapping {
+        let seasons = self.seasons.read().await;
+        let empty = BTreeMap::new();
+        let discs = seasons
+            .get(&season_key(&series.show, series.season))
+            .unwrap_or(&empty);
+
+        let disc = series
+            .disc
+            .unwrap_or_else(|| discs.keys().max().map_or(1, |last| last + 1));
+
+        let first_episode = discs
+            .range(..disc)
+            .flat_map(|(_, episodes)| episodes.iter().copied())
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        let episodes: Vec<EpisodeFile> = titles
+            .iter()
+            .filter_map(|id| disc_info.titles.get(*id))
+            .zip(first_episode..)
+            .map(|(title, episode)| EpisodeFile {
+                file: title
+                    .output_file_name
+                    .clone()
+                    .unwrap_or_else(|| format!("title_t{:02}.mkv", title.id)),
+                title_id: title.id,
+                duration: title.duration,
+                episode: Some(episode),
+            })
+            .collect();
+
+        info!(
+            "{} season {} disc {}: episodes {}-{}",
+            series.show,
+            series.season,
+            disc,
+            first_episode,
+            first_episode + episodes.len() as u32 - 1
+        );
+
+        EpisodeMapping {
+            show: series.show.clone(),
+            season: series.season,
+            disc,
+            episodes,
+            confirmed: false,
+        }
+    }
+
+    /// Remembers a ripped disc's episodes, so the next disc of the season
+    /// carries on after them.
+    pub async fn record(&self, mapping: &EpisodeMapping) -> Result<(), Error> {
+        let mut seasons = self.seasons.write().await;
+
+        seasons
+            .entry(season_key(&mapping.show, mapping.season))
+            .or_default()
+            .insert(
+                mapping.disc,
+                mapping.episodes.iter().filter_map(|e| e.episode).collect(),
+            );
+
+        self.save(&seasons).await
+    }
+
+    /// Holds a ripped series disc back from encoding until its mapping is
+    /// confirmed.
+    pub async fn park(&self, folder: PathBuf, mapping: EpisodeMapping) {
+        let id = folder
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        self.pending.write().await.insert(
+            id.clone(),
+            PendingMapping {
+                id,
+                folder,
+                mapping,
+            },
+        );
+    }
+
+    pub async fn is_pending(&self, folder: &Path) -> bool {
+        self.pending
+            .read()
+            .await
+            .values()
+            .any(|pending| pending.folder == folder)
+    }
+
+    pub async fn get_pending(&self) -> Vec<PendingMapping> {
+        let mut pending: Vec<_> = self.pending.read().await.values().cloned().collect();
+        pending.sort_by(|a, b| a.id.cmp(&b.id));
+        pending
+    }
+
+    /// Applies the user's edits to a pending mapping and marks it confirmed,
+    /// returning the rip folder so it can be queued for encoding.
+    pub async fn confirm(&self, id: &str, request: ConfirmRequest) -> Result<PathBuf, SeriesError> {
+        let mut pending = self.pending.write().await;
+        let entry = pending
+            .get(id)
+            .ok_or_else(|| SeriesError::NotFound(id.to_owned()))?;
+
+        let mut mapping = entry.mapping.clone();
+        let old_key = season_key(&mapping.show, mapping.season);
+
+        if let Some(show) = request.show.filter(|show| !show.trim().is_empty()) {
+            mapping.show = show.trim().to_owned();
+        }
+        if let Some(season) = request.season {
+            mapping.season = season;
+        }
+
+        for assignment in request.episodes {
+            let file = mapping
+                .episodes
+                .iter_mut()
+                .find(|e| e.file == assignment.file)
+                .ok_or_else(|| SeriesError::Invalid(format!("unknown file {}", assignment.file)))?;
+            file.episode = assignment.episode;
+        }
+
+        let mut numbers: Vec<u32> = mapping.episodes.iter().filter_map(|e| e.episode).collect();
+        numbers.sort_unstable();
+        if let Some(pair) = numbers.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(SeriesError::Invalid(format!(
+                "episode {} is assigned more than once",
+                pair[0]
+            )));
+        }
+
+        mapping.confirmed = true;
+
+        let meta_path = entry.folder.join("meta.toml");
+        let write_meta = async {
+            let mut meta: DiscMetadata = toml::from_slice(&fs::read(&meta_path).await?)?;
+            meta.series = Some(mapping.clone());
+            fs::write(&meta_path, toml::to_string(&meta)?).await?;
+            Ok::<_, Error>(())
+        };
+        write_meta.await.map_err(SeriesError::Failed)?;
+
+        {
+            let mut seasons = self.seasons.write().await;
+
+            if let Some(discs) = seasons.get_mut(&old_key) {
+                discs.remove(&mapping.disc);
+            }
+
+            seasons
+                .entry(season_key(&mapping.show, mapping.season))
+                .or_default()
+                .insert(mapping.disc, numbers);
+
+            self.save(&seasons).await.map_err(SeriesError::Failed)?;
+        }
+
+        let folder = entry.folder.clone();
+        pending.remove(id);
+
+        Ok(folder)
+    }
+}
+
+/// Recognises series discs, first by labels like `FRIENDS_S1_D2` or
+/// `SHOW_SEASON_2_DISC_1`, then by a run of similar length episode titles.
+/// A run next to a feature-length title is a movie with extras, unless that
+/// title just plays the episodes back to back.
+pub fn detect(disc: &Disc, disc_info: &DiscInfo, selection: &Selection) -> Option<SeriesDisc> {
+    let label = disc
+        .properties
+        .get("ID_FS_LABEL")
+        .or(disc_info.volume_name.as_ref());
+
+    if let Some(series) = label.and_then(|label| parse_label(label)) {
+        return Some(series);
+    }
+
+    let episodes = disc_info.select_titles(&Selection {
+        strategy: SelectionStrategy::Episodes,
+        ..selection.clone()
+    });
+
+    let durations: Vec<f64> = episodes
+        .iter()
+        .filter_map(|id| disc_info.titles.get(*id))
+        .map(|t| t.duration.as_secs_f64())
+        .collect();
+
+    let shortest = durations.iter().copied().fold(f64::INFINITY, f64::min);
+    let longest = durations.iter().copied().fold(0.0, f64::max);
+
+    let total: f64 = durations.iter().sum();
+    let feature = disc_info.titles.iter().any(|t| {
+        t.duration > selection.episode_max_length
+            && (t.duration.as_secs_f64() - total).abs() > total * PLAY_ALL_TOLERANCE
+    });
+
+    if durations.len() >= MIN_CLUSTER_SIZE && longest <= shortest * CLUSTER_SPREAD && !feature {
+        return Some(SeriesDisc {
+            show: disc.title(),
+            season: 1,
+            disc: None,
+        });
+    }
+
+    None
+}
+
+/// The number following one of `prefixes` in a token, e.g. 2 from "D2".
+fn number_after(token: &str, prefixes: &[&str]) -> Option<u32> {
+    prefixes.iter().find_map(|prefix| {
+        let rest = token.strip_prefix(prefix)?;
+        if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        rest.parse().ok()
+    })
+}
+
+fn parse_label(label: &str) -> Option<SeriesDisc> {
+    let tokens: Vec<String> = label
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_uppercase())
+        .collect();
+
+    // a number either glued to the keyword ("S1", "DISC2") or the token after it
+    let numbered = |index: usize, prefixes: &[&str], words: &[&str]| -> Option<(u32, usize)> {
+        let token = tokens.get(index)?;
+        if let Some(number) = number_after(token, prefixes) {
+            return Some((number, 1));
+        }
+        if words.contains(&token.as_str()) {
+            return tokens
+                .get(index + 1)
+                .and_then(|next| next.parse().ok())
+                .map(|number| (number, 2));
+        }
+        None
+    };
+
+    for index in 0..tokens.len() {
+        // "S1D2"
+        if let Some((season, disc)) = tokens[index].split_once('D')
+            && let (Some(season), Ok(disc)) = (number_after(season, &["S"]), disc.parse())
+        {
+            return series(&tokens[..index], season, Some(disc));
+        }
+
+        let Some((season, used)) = numbered(index, &["SEASON", "S"], &["SEASON"]) else {
+            continue;
+        };
+
+        let disc =
+            numbered(index + used, &["DISC", "DISK", "D"], &["DISC", "DISK"]).map(|(disc, _)| disc);
+
+        return series(&tokens[..index], season, disc);
+    }
+
+    None
+}
+
+fn series(show: &[String], season: u32, disc: Option<u32>) -> Option<SeriesDisc> {
+    if show.is_empty() {
+        return None;
+    }
+
+    Some(SeriesDisc {
+        show: show.join(" ").to_title_case(),
+        season,
+        disc,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::makemkv::TitleInfo;
+
+    fn parse(label: &str) -> Option<(String, u32, Option<u32>)> {
+        parse_label(label).map(|disc| (disc.show, disc.season, disc.disc))
+    }
+
+    #[test]
+    fn parses_compact_labels() {
+        assert_eq!(
+            parse("THE_WIRE_S1D2"),
+            Some(("The Wire".to_owned(), 1, Some(2)))
+        );
+        assert_eq!(
+            parse("THE_WIRE_S3_D4"),
+            Some(("The Wire".to_owned(), 3, Some(4)))
+        );
+        assert_eq!(
+            parse("FRIENDS_S10_DISC1"),
+            Some(("Friends".to_owned(), 10, Some(1)))
+        );
+    }
+
+    #[test]
+    fn parses_spelled_out_labels() {
+        assert_eq!(
+            parse("BAND OF BROTHERS SEASON 1 DISC 3"),
+            Some(("Band Of Brothers".to_owned(), 1, Some(3)))
+        );
+        assert_eq!(
+            parse("lost-season2-disk1"),
+            Some(("Lost".to_owned(), 2, Some(1)))
+        );
+    }
+
+    #[test]
+    fn leaves_the_disc_out_when_unlabelled() {
+        assert_eq!(parse("SOPRANOS_S2"), Some(("Sopranos".to_owned(), 2, None)));
+        assert_eq!(
+            parse("SOPRANOS SEASON 2"),
+            Some(("Sopranos".to_owned(), 2, None))
+        );
+    }
+
+    #[test]
+    fn rejects_labels_without_a_show_or_season() {
+        assert_eq!(parse("S1D1"), None);
+        assert_eq!(parse("SEASON 1"), None);
+        assert_eq!(parse("BLADE_RUNNER"), None);
+        assert_eq!(parse("SPIDER_MAN_2"), None);
+        assert_eq!(parse("THE_SEASONS"), None);
+    }
+
+    fn unlabelled(minutes: &[u64]) -> (Disc, DiscInfo) {
+        let disc = Disc {
+            name: "/dev/sr0".to_owned(),
+            r#type: None,
+            properties: [("ID_FS_LABEL".to_owned(), "DEADWOOD".to_owned())].into(),
+            detected_at: chrono::Local::now(),
+        };
+
+        let disc_info = DiscInfo {
+            titles: minutes
+                .iter()
+                .enumerate()
+                .map(|(id, minutes)| TitleInfo {
+                    id,
+                    duration: Duration::from_secs(minutes * 60),
+                    segment_map: id.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        (disc, disc_info)
+    }
+
+    fn selection() -> Selection {
+        Selection {
+            strategy: SelectionStrategy::AllAbove,
+            min_length: Duration::from_secs(10 * 60),
+            episode_min_length: Duration::from_secs(20 * 60),
+            episode_max_length: Duration::from_secs(65 * 60),
+            titles: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn detects_unlabelled_episode_discs() {
+        let (disc, disc_info) = unlabelled(&[52, 55, 54, 3]);
+
+        let series = detect(&disc, &disc_info, &selection()).unwrap();
+        assert_eq!((series.show.as_str(), series.season), ("Deadwood", 1));
+        assert_eq!(series.disc, None);
+    }
+
+    #[test]
+    fn detects_episode_discs_with_a_play_all_title() {
+        let (disc, disc_info) = unlabelled(&[161, 52, 55, 54]);
+
+        assert!(detect(&disc, &disc_info, &selection()).is_some());
+    }
+
+    #[test]
+    fn movies_with_extras_are_not_series() {
+        let (disc, disc_info) = unlabelled(&[128, 24, 26, 25, 2]);
+
+        assert!(detect(&disc, &disc_info, &selection()).is_none());
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::handbrake::JobStatus;
+
+/// Keeps a JSON snapshot of every HandBrake job under the state directory,
+/// so queued work and job history survive a restart.
+#[derive(Clone, Debug)]
+pub struct JobStore {
+    path: PathBuf,
+}
+
+impl JobStore {
+    pub fn new(state_dir: &Path) -> JobStore {
+        JobStore {
+            path: state_dir.join("jobs.json"),
+        }
+    }
+
+    pub async fn load(&self) -> Result<HashMap<String, JobStatus>, Error> {
+        if !self.path.is_file() {
+            return Ok(HashMap::new());
+        }
+
+        Ok(serde_json::from_slice(&fs::read(&self.path).await?)?)
+    }
+
+    pub async fn save(&self, jobs: &HashMap<String, JobStatus>) -> Result<(), Error> {
+        write_json_atomic(&self.path, jobs).await
+    }
+}
+
+/// Writes a value as JSON to a temporary file first and renames it into
+/// place, so a crash mid-write never leaves a truncated file behind.
+pub async fn write_json_atomic(path: &Path, value: &impl Serialize) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+
+    let tmp = path.with_extension("json.tmp");
+
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?).await?;
+    fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
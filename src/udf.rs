@@ -0,0 +1,677 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind},
+    os::unix::fs::FileExt,
+};
+
+pub const SECTOR_SIZE: usize = 2048;
+/// Where the anchor volume descriptor pointer lives.
+const ANCHOR: u64 = 256;
+
+const ANCHOR_TAG: u16 = 2;
+const PARTITION_TAG: u16 = 5;
+const LOGICAL_VOLUME_TAG: u16 = 6;
+const TERMINATING_TAG: u16 = 8;
+const FILE_SET_TAG: u16 = 256;
+const FILE_IDENTIFIER_TAG: u16 = 257;
+const ALLOCATION_EXTENT_TAG: u16 = 258;
+const FILE_ENTRY_TAG: u16 = 261;
+const EXTENDED_FILE_ENTRY_TAG: u16 = 266;
+/// How far into the volume descriptor sequence to look, it's 16 sectors on
+/// every disc we've seen.
+const MAX_DESCRIPTORS: u64 = 64;
+/// Directories and the files we read are small, anything bigger than this
+/// means the file system is corrupt or we've misread it.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+pub fn tag(sector: &[u8]) -> u16 {
+    u16::from_le_bytes([sector[0], sector[1]])
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le_u32(bytes: &[u8]) -> u64 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_sector(file: &File, sector: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; SECTOR_SIZE];
+    file.read_exact_at(&mut buf, sector * SECTOR_SIZE as u64)?;
+    Ok(buf)
+}
+
+/// The descriptors in the main volume descriptor sequence, or nothing if the
+/// disc isn't UDF.
+pub fn volume_descriptors(file: &File) -> io::Result<Vec<Vec<u8>>> {
+    let anchor = match read_sector(file, ANCHOR) {
+        Ok(anchor) if tag(&anchor) == ANCHOR_TAG => anchor,
+        _ => return Ok(Vec::new()),
+    };
+
+    // the main volume descriptor sequence extent: length in bytes, then sector
+    let length = le_u32(&anchor[16..20]) / SECTOR_SIZE as u64;
+    let location = le_u32(&anchor[20..24]);
+
+    let mut descriptors = Vec::new();
+
+    for sector in location..location + length.min(MAX_DESCRIPTORS) {
+        let descriptor = read_sector(file, sector)?;
+
+        if tag(&descriptor) == TERMINATING_TAG {
+            break;
+        }
+
+        descriptors.push(descriptor);
+    }
+
+    Ok(descriptors)
+}
+
+/// A logical block address: a partition reference and a block within it.
+#[derive(Copy, Clone, Debug)]
+struct Address {
+    partition: u16,
+    block: u64,
+}
+
+/// A run of blocks making up part of a file.
+#[derive(Copy, Clone, Debug)]
+struct Extent {
+    at: Address,
+    length: u64,
+}
+
+#[derive(Debug)]
+enum Partition {
+    Physical {
+        start: u64,
+    },
+    /// UDF 2.50's metadata partition, as used on Blu-rays, whose blocks live
+    /// wherever the metadata file's extents put them.
+    Metadata {
+        start: u64,
+        extents: Vec<(u64, u64)>,
+    },
+}
+
+/// An ICB (file entry) read off the disc: its data, either inline or spread
+/// over extents.
+enum Contents {
+    Embedded(Vec<u8>),
+    Extents(Vec<Extent>),
+}
+
+struct Entry {
+    size: u64,
+    contents: Contents,
+}
+
+/// A read-only view of the UDF file system on a disc, enough to read a few
+/// small files by path.
+pub struct Udf<'a> {
+    file: &'a File,
+    /// By partition reference number, i.e. the logical volume's partition maps.
+    partitions: Vec<Partition>,
+    root: Address,
+}
+
+impl<'a> Udf<'a> {
+    pub fn open(file: &'a File) -> io::Result<Udf<'a>> {
+        let descriptors = volume_descriptors(file)?;
+
+        if descriptors.is_empty() {
+            return Err(invalid("no UDF file system found"));
+        }
+
+        let mut partition_starts = Vec::new();
+        let mut logical_volume = None;
+
+        for descriptor in descriptors {
+            match tag(&descriptor) {
+                PARTITION_TAG => {
+                    partition_starts.push((le_u16(&descriptor[22..]), le_u32(&descriptor[188..])))
+                }
+                LOGICAL_VOLUME_TAG => logical_volume = Some(descriptor),
+                _ => {}
+            }
+        }
+
+        let logical_volume =
+            logical_volume.ok_or_else(|| invalid("no UDF logical volume descriptor"))?;
+
+        if le_u32(&logical_volume[212..]) != SECTOR_SIZE as u64 {
+            return Err(invalid("unsupported UDF block size"));
+        }
+
+        let partition_start = |number: u16| {
+            partition_starts
+                .iter()
+                .find(|(n, _)| *n == number)
+                .map(|(_, start)| *start)
+                .ok_or_else(|| invalid(format!("no UDF partition {}", number)))
+        };
+
+        let map_count = le_u32(&logical_volume[268..]);
+        let mut maps = &logical_volume[440..];
+        let mut metadata_files = Vec::new();
+        let mut partitions = Vec::new();
+
+        for index in 0..map_count {
+            let (kind, length) = match maps {
+                [kind, length, ..] => (*kind, *length as usize),
+                _ => return Err(invalid("bad UDF partition map")),
+            };
+
+            if length < 6 || length > maps.len() {
+                return Err(invalid("bad UDF partition map"));
+            }
+
+            let map = &maps[..length];
+
+            match kind {
+                1 => partitions.push(Partition::Physical {
+                    start: partition_start(le_u16(&map[4..]))?,
+                }),
+                2 if map[5..].starts_with(b"*UDF Metadata Partition") => {
+                    let start = partition_start(le_u16(&map[38..]))?;
+                    metadata_files.push((index as usize, start, le_u32(&map[40..])));
+                    partitions.push(Partition::Metadata {
+                        start,
+                        extents: Vec::new(),
+                    });
+                }
+                // sparable partitions only remap blocks that have worn out
+                2 if map[5..].starts_with(b"*UDF Sparable Partition") => {
+                    partitions.push(Partition::Physical {
+                        start: partition_start(le_u16(&map[38..]))?,
+                    })
+                }
+                _ => return Err(invalid("unsupported UDF partition map")),
+            }
+
+            maps = &maps[length..];
+        }
+
+        let mut udf = Udf {
+            file,
+            partitions,
+            root: Address {
+                partition: 0,
+                block: 0,
+            },
+        };
+
+        // the metadata file lives in the physical partition, so it's read as
+        // if it were one
+        for (index, start, location) in metadata_files {
+            udf.partitions.push(Partition::Physical { start });
+            let physical = (udf.partitions.len() - 1) as u16;
+
+            let entry = udf.read_entry(Address {
+                partition: physical,
+                block: location,
+            })?;
+
+            udf.partitions.pop();
+
+            let Contents::Extents(extents) = entry.contents else {
+                return Err(invalid("embedded UDF metadata file"));
+            };
+
+            udf.partitions[index] = Partition::Metadata {
+                start,
+                extents: extents
+                    .iter()
+                    .map(|extent| (extent.at.block, extent.length.div_ceil(SECTOR_SIZE as u64)))
+                    .collect(),
+            };
+        }
+
+        let file_set = long_ad(&logical_volume[248..]).at;
+        let file_set = udf.read_block(file_set)?;
+
+        if tag(&file_set) != FILE_SET_TAG {
+            return Err(invalid("no UDF file set descriptor"));
+        }
+
+        udf.root = long_ad(&file_set[400..]).at;
+
+        Ok(udf)
+    }
+
+    fn sector(&self, address: Address) -> io::Result<u64> {
+        match self.partitions.get(address.partition as usize) {
+            Some(Partition::Physical { start }) => Ok(start + address.block),
+            Some(Partition::Metadata { start, extents }) => {
+                let mut block = address.block;
+
+                for (location, length) in extents {
+                    if block < *length {
+                        return Ok(start + location + block);
+                    }
+                    block -= length;
+                }
+
+                Err(invalid("block outside the UDF metadata partition"))
+            }
+            None => Err(invalid(format!(
+                "no UDF partition reference {}",
+                address.partition
+            ))),
+        }
+    }
+
+    fn read_block(&self, address: Address) -> io::Result<Vec<u8>> {
+        read_sector(self.file, self.sector(address)?)
+    }
+
+    fn read_entry(&self, address: Address) -> io::Result<Entry> {
+        let block = self.read_block(address)?;
+
+        let (ea_length, ad_length, ads_at) = match tag(&block) {
+            FILE_ENTRY_TAG => (le_u32(&block[168..]), le_u32(&block[172..]), 176),
+            EXTENDED_FILE_ENTRY_TAG => (le_u32(&block[208..]), le_u32(&block[212..]), 216),
+            _ => return Err(invalid("not a UDF file entry")),
+        };
+
+        let size = le_u64(&block[56..]);
+        let start = ads_at + ea_length as usize;
+        let ads = block
+            .get(start..start + ad_length as usize)
+            .ok_or_else(|| invalid("bad UDF file entry"))?;
+
+        // the allocation descriptor type is in the ICB tag's flags
+        let contents = match le_u16(&block[34..]) & 0x7 {
+            0 => Contents::Extents(self.extents(ads, address.partition, 8)?),
+            1 => Contents::Extents(self.extents(ads, address.partition, 16)?),
+            3 => Contents::Embedded(ads.to_vec()),
+            _ => return Err(invalid("unsupported UDF allocation descriptors")),
+        };
+
+        Ok(Entry { size, contents })
+    }
+
+    /// Reads short (8 byte) or long (16 byte) allocation descriptors,
+    /// following any continuation extents.
+    fn extents(&self, ads: &[u8], partition: u16, size: usize) -> io::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut ads = ads.to_vec();
+
+        loop {
+            let mut next = None;
+
+            for ad in ads.chunks_exact(size) {
+                let extent = match size {
+                    8 => Extent {
+                        at: Address {
+                            partition,
+                            block: le_u32(&ad[4..]),
+                        },
+                        length: le_u32(ad),
+                    },
+                    _ => long_ad(ad),
+                };
+
+                // the top two bits of the length say what kind of extent it is
+                let (kind, length) = (extent.length >> 30, extent.length & 0x3FFF_FFFF);
+
+                match kind {
+                    _ if length == 0 => break,
+                    3 => {
+                        next = Some(extent.at);
+                        break;
+                    }
+                    _ => extents.push(Extent { length, ..extent }),
+                }
+            }
+
+            let Some(at) = next else {
+                return Ok(extents);
+            };
+
+            let block = self.read_block(at)?;
+
+            if tag(&block) != ALLOCATION_EXTENT_TAG {
+                return Err(invalid("bad UDF allocation extent"));
+            }
+
+            let length = le_u32(&block[20..]) as usize;
+            ads = block
+                .get(24..24 + length)
+                .ok_or_else(|| invalid("bad UDF allocation extent"))?
+                .to_vec();
+
+            if extents.len() > MAX_FILE_SIZE as usize / SECTOR_SIZE {
+                return Err(invalid("too many UDF extents"));
+            }
+        }
+    }
+
+    fn read_contents(&self, entry: Entry) -> io::Result<Vec<u8>> {
+        if entry.size > MAX_FILE_SIZE {
+            return Err(invalid("UDF file too large"));
+        }
+
+        let mut data = match entry.contents {
+            Contents::Embedded(data) => data,
+            Contents::Extents(extents) => {
+                let mut data = Vec::with_capacity(entry.size as usize);
+
+                for extent in extents {
+                    let blocks = extent.length.div_ceil(SECTOR_SIZE as u64);
+
+                    for block in 0..blocks {
+                        if data.len() as u64 >= entry.size {
+                            break;
+                        }
+
+                        data.extend(self.read_block(Address {
+                            block: extent.at.block + block,
+                            ..extent.at
+                        })?);
+                    }
+                }
+
+                data
+            }
+        };
+
+        data.truncate(entry.size as usize);
+        Ok(data)
+    }
+
+    /// The names and entries in a directory.
+    fn list(&self, directory: Address) -> io::Result<Vec<(String, Address)>> {
+        let data = self.read_contents(self.read_entry(directory)?)?;
+        let mut children = Vec::new();
+        let mut at = 0;
+
+        while at + 38 <= data.len() {
+            let fid = &data[at..];
+
+            if tag(fid) != FILE_IDENTIFIER_TAG {
+                return Err(invalid("bad UDF file identifier"));
+            }
+
+            let characteristics = fid[18];
+            let name_length = fid[19] as usize;
+            let implementation_length = le_u16(&fid[36..]) as usize;
+            let name_at = 38 + implementation_length;
+
+            let name = fid
+                .get(name_at..name_at + name_length)
+                .ok_or_else(|| invalid("bad UDF file identifier"))?;
+
+            // skip the parent directory entry and deleted files
+            if characteristics & 0x0C == 0 {
+                children.push((decode_name(name), long_ad(&fid[20..]).at));
+            }
+
+            at += (name_at + name_length).next_multiple_of(4);
+        }
+
+        Ok(children)
+    }
+
+    fn find(&self, path: &[&str]) -> io::Result<Address> {
+        let mut address = self.root;
+
+        for component in path {
+            address = self
+                .list(address)?
+                .into_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(component))
+                .map(|(_, address)| address)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("{} not found on disc", path.join("/")),
+                    )
+                })?;
+        }
+
+        Ok(address)
+    }
+
+    /// The names of the entries in a directory, e.g. `["VIDEO_TS"]`.
+    pub fn read_dir(&self, path: &[&str]) -> io::Result<Vec<String>> {
+        Ok(self
+            .list(self.find(path)?)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// The contents of a file, e.g. `["AACS", "Unit_Key_RO.inf"]`.
+    pub fn read_file(&self, path: &[&str]) -> io::Result<Vec<u8>> {
+        self.read_contents(self.read_entry(self.find(path)?)?)
+    }
+}
+
+fn long_ad(ad: &[u8]) -> Extent {
+    Extent {
+        at: Address {
+            partition: le_u16(&ad[8..]),
+            block: le_u32(&ad[4..]),
+        },
+        length: le_u32(ad),
+    }
+}
+
+/// Decodes an OSTA compressed unicode file name: a compression id of 8 for
+/// one byte per character or 16 for big-endian UTF-16.
+fn decode_name(name: &[u8]) -> String {
+    match name.split_first() {
+        Some((16, rest)) => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        Some((_, rest)) => rest.iter().map(|&b| b as char).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    const PARTITION_START: usize = 300;
+
+    fn put_u16(block: &mut [u8], at: usize, value: u16) {
+        block[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(block: &mut [u8], at: usize, value: u32) {
+        block[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn descriptor(tag: u16) -> Vec<u8> {
+        let mut block = vec![0; SECTOR_SIZE];
+        put_u16(&mut block, 0, tag);
+        block
+    }
+
+    /// A file identifier pointing at `block` in partition 0.
+    fn fid(name: &str, characteristics: u8, block: u32) -> Vec<u8> {
+        let mut fid = vec![0; 38];
+        put_u16(&mut fid, 0, FILE_IDENTIFIER_TAG);
+        fid[18] = characteristics;
+        fid[19] = if name.is_empty() {
+            0
+        } else {
+            name.len() as u8 + 1
+        };
+        put_u32(&mut fid, 20, SECTOR_SIZE as u32);
+        put_u32(&mut fid, 24, block);
+
+        if !name.is_empty() {
+            fid.push(8);
+            fid.extend(name.bytes());
+        }
+
+        fid.resize(fid.len().next_multiple_of(4), 0);
+        fid
+    }
+
+    /// A file entry holding its data inline.
+    fn embedded_entry(tag: u16, data: &[u8]) -> Vec<u8> {
+        let mut block = descriptor(tag);
+        let (lengths, ads_at) = match tag {
+            FILE_ENTRY_TAG => (168, 176),
+            _ => (208, 216),
+        };
+
+        put_u16(&mut block, 34, 3);
+        block[56..64].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        put_u32(&mut block, lengths + 4, data.len() as u32);
+        block[ads_at..ads_at + data.len()].copy_from_slice(data);
+        block
+    }
+
+    /// A disc with a VIDEO_TS directory holding two files. With `metadata`
+    /// its partition is mapped through a UDF 2.50 metadata file, like a
+    /// Blu-ray's, which covers the first six blocks of the partition.
+    fn image(metadata: bool) -> Vec<u8> {
+        let mut sectors = vec![vec![0; SECTOR_SIZE]; PARTITION_START + 8];
+
+        let mut anchor = descriptor(ANCHOR_TAG);
+        put_u32(&mut anchor, 16, 3 * SECTOR_SIZE as u32);
+        put_u32(&mut anchor, 20, 32);
+        sectors[ANCHOR as usize] = anchor;
+
+        let mut partition = descriptor(PARTITION_TAG);
+        put_u32(&mut partition, 188, PARTITION_START as u32);
+        sectors[32] = partition;
+
+        let mut logical_volume = descriptor(LOGICAL_VOLUME_TAG);
+        put_u32(&mut logical_volume, 212, SECTOR_SIZE as u32);
+        put_u32(&mut logical_volume, 248, SECTOR_SIZE as u32);
+        put_u32(&mut logical_volume, 268, 1);
+
+        if metadata {
+            logical_volume[440] = 2;
+            logical_volume[441] = 64;
+            logical_volume[445..468].copy_from_slice(b"*UDF Metadata Partition");
+            put_u32(&mut logical_volume, 480, 6);
+
+            let mut metadata_file = descriptor(FILE_ENTRY_TAG);
+            metadata_file[56..64].copy_from_slice(&(6 * SECTOR_SIZE as u64).to_le_bytes());
+            put_u32(&mut metadata_file, 172, 8);
+            put_u32(&mut metadata_file, 176, 6 * SECTOR_SIZE as u32);
+            sectors[PARTITION_START + 6] = metadata_file;
+        } else {
+            logical_volume[440] = 1;
+            logical_volume[441] = 6;
+        }
+
+        sectors[33] = logical_volume;
+
+        sectors[34] = descriptor(TERMINATING_TAG);
+
+        // the file set, pointing at the root directory in block 1
+        let mut file_set = descriptor(FILE_SET_TAG);
+        put_u32(&mut file_set, 400, SECTOR_SIZE as u32);
+        put_u32(&mut file_set, 404, 1);
+        sectors[PARTITION_START] = file_set;
+
+        let root = [fid("", 0x0A, 1), fid("VIDEO_TS", 0x02, 2)].concat();
+        sectors[PARTITION_START + 1] = embedded_entry(FILE_ENTRY_TAG, &root);
+
+        let video_ts = [
+            fid("", 0x0A, 1),
+            fid("VIDEO_TS.IFO", 0, 3),
+            fid("VTS_01_0.IFO", 0, 5),
+        ]
+        .concat();
+        sectors[PARTITION_START + 2] = embedded_entry(FILE_ENTRY_TAG, &video_ts);
+
+        // a file stored in an extent rather than inline
+        let mut ifo = descriptor(FILE_ENTRY_TAG);
+        ifo[56] = 5;
+        put_u32(&mut ifo, 172, 8);
+        put_u32(&mut ifo, 176, 5);
+        put_u32(&mut ifo, 180, 4);
+        sectors[PARTITION_START + 3] = ifo;
+        sectors[PARTITION_START + 4][..5].copy_from_slice(b"hello");
+
+        sectors[PARTITION_START + 5] = embedded_entry(EXTENDED_FILE_ENTRY_TAG, b"world");
+
+        sectors.concat()
+    }
+
+    fn with_image(metadata: bool, test: impl FnOnce(&Udf)) {
+        // tests run in parallel, so each gets its own image
+        static IMAGES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "torn-udf-{}-{}.img",
+            std::process::id(),
+            IMAGES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, image(metadata)).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let result = Udf::open(&file).map(|udf| test(&udf));
+
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn lists_directories() {
+        with_image(false, |udf| {
+            assert_eq!(udf.read_dir(&[]).unwrap(), ["VIDEO_TS"]);
+            assert_eq!(
+                udf.read_dir(&["video_ts"]).unwrap(),
+                ["VIDEO_TS.IFO", "VTS_01_0.IFO"]
+            );
+        });
+    }
+
+    #[test]
+    fn reads_files() {
+        for metadata in [false, true] {
+            with_image(metadata, |udf| {
+                assert_eq!(
+                    udf.read_file(&["VIDEO_TS", "VIDEO_TS.IFO"]).unwrap(),
+                    b"hello"
+                );
+                assert_eq!(
+                    udf.read_file(&["VIDEO_TS", "VTS_01_0.IFO"]).unwrap(),
+                    b"world"
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn missing_files_are_not_found() {
+        with_image(false, |udf| {
+            let err = udf.read_file(&["AACS", "Unit_Key_RO.inf"]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        });
+    }
+
+    #[test]
+    fn decodes_names() {
+        assert_eq!(decode_name(b"\x08VIDEO_TS"), "VIDEO_TS");
+        assert_eq!(decode_name(b"\x10\x00B\x00D"), "BD");
+        assert_eq!(decode_name(b""), "");
+    }
+}
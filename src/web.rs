@@ -3,7 +3,8 @@ use std::time::Duration;
 
 use axum::{
     Router,
-    extract::State,
+    extract::{Path, Query, State},
+    http::StatusCode,
     response::Json,
     routing::{get, post},
 };
@@ -12,17 +13,32 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use tower_http::services::ServeDir;
 
-use crate::config::Settings;
+use crate::catalog::{Catalog, CatalogEntry, Decision, DuplicatePrompt};
+use crate::cdrom::{self, DriveState, TrayLocks};
+use crate::config::{self, Settings};
 use crate::disc::{Disc, DiscType};
-use crate::handbrake::HandbrakeProcess;
+use crate::handbrake::{
+    FileStatus, HandbrakeProcess, JobControlError, JobState, JobStatus, StateTransition,
+};
+use crate::makemkv::{RipStatus, RipStatuses};
+use crate::metadata::MediaLookup;
+use crate::monitor;
+use crate::music::{self, Review, Reviews};
+use crate::series::{ConfirmRequest, PendingMapping, SeriesError, SeriesStore};
+
+/// How many finished jobs the dashboard shows.
+const RECENT_HISTORY: usize = 10;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DriveStatus {
+    pub id: String,
     pub device: String,
-    pub disc_present: bool,
+    /// None when the drive's tray state can't be read.
+    pub state: Option<DriveState>,
     pub disc_type: Option<DiscType>,
     pub disc_title: Option<String>,
     pub status: String,
+    pub rip: Option<RipStatus>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -32,14 +48,55 @@ pub struct HandbrakeJob {
     pub destination: String,
     pub progress: f32,
     pub status: String,
+    pub state: JobState,
+    pub transitions: Vec<StateTransition>,
     pub started_at: String,
+    pub file_progress: f32,
+    pub fps: f32,
+    pub avg_fps: f32,
+    pub eta_seconds: Option<u64>,
+    pub worker: Option<usize>,
+    pub priority: i32,
+    pub lookup: MediaLookup,
+    pub log: Vec<String>,
+    pub files: Vec<FileStatus>,
+    pub ignored: Vec<String>,
+}
+
+impl From<JobStatus> for HandbrakeJob {
+    fn from(job: JobStatus) -> Self {
+        HandbrakeJob {
+            id: job.id,
+            source: job.source,
+            destination: job.destination,
+            progress: job.progress,
+            status: job.state.to_string(),
+            state: job.state,
+            transitions: job.transitions,
+            started_at: job.started_at,
+            file_progress: job.file_progress,
+            fps: job.fps,
+            avg_fps: job.avg_fps,
+            eta_seconds: job.eta_seconds,
+            worker: job.worker,
+            priority: job.priority,
+            lookup: job.lookup,
+            log: job.log,
+            files: job.files,
+            ignored: job.ignored,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SystemStatus {
     pub drives: Vec<DriveStatus>,
     pub handbrake_jobs: Vec<HandbrakeJob>,
+    pub job_history: Vec<HandbrakeJob>,
     pub queue_size: usize,
+    pub reviews: Vec<Review>,
+    pub pending_episodes: Vec<PendingMapping>,
+    pub duplicates: Vec<DuplicatePrompt>,
 }
 
 #[derive(Clone)]
@@ -47,22 +104,41 @@ pub struct AppState {
     pub settings: Settings,
     pub system_status: Arc<RwLock<SystemStatus>>,
     pub handbrake_process: HandbrakeProcess,
+    pub rip_statuses: RipStatuses,
+    pub reviews: Reviews,
+    pub series: SeriesStore,
+    pub catalog: Catalog,
+    pub tray_locks: TrayLocks,
 }
 
 pub async fn run_web_server(
     settings: Settings,
     handbrake_process: HandbrakeProcess,
+    rip_statuses: RipStatuses,
+    reviews: Reviews,
+    series: SeriesStore,
+    catalog: Catalog,
+    tray_locks: TrayLocks,
 ) -> Result<(), failure::Error> {
     let system_status = Arc::new(RwLock::new(SystemStatus {
         drives: Vec::new(),
         handbrake_jobs: Vec::new(),
+        job_history: Vec::new(),
         queue_size: 0,
+        reviews: Vec::new(),
+        pending_episodes: Vec::new(),
+        duplicates: Vec::new(),
     }));
 
     let app_state = AppState {
         settings: settings.clone(),
         system_status: system_status.clone(),
         handbrake_process,
+        rip_statuses,
+        reviews,
+        series,
+        catalog,
+        tray_locks,
     };
 
     // Start background task to update system status
@@ -85,8 +161,26 @@ pub async fn run_web_server(
 
     let app = Router::new()
         .route("/", get(serve_app))
+        .route("/library", get(serve_library))
         .route("/api/status", get(get_status))
-        .route("/api/eject/:device", post(eject_disc))
+        .route("/api/jobs", get(get_jobs))
+        .route("/api/jobs/history", get(get_job_history))
+        .route("/api/jobs/:id/cancel", post(cancel_job))
+        .route("/api/jobs/:id/pause", post(pause_job))
+        .route("/api/jobs/:id/resume", post(resume_job))
+        .route("/api/jobs/:id/retry", post(retry_job))
+        .route("/api/jobs/:id/priority", post(set_job_priority))
+        .route("/api/music/reviews", get(get_reviews))
+        .route("/api/music/reviews/:disc_id/dismiss", post(dismiss_review))
+        .route("/api/series/pending", get(get_pending_episodes))
+        .route("/api/series/:id/confirm", post(confirm_episodes))
+        .route("/api/catalog", get(get_catalog))
+        .route("/api/duplicates/:id/:decision", post(decide_duplicate))
+        .route("/api/eject/:id", post(eject_disc))
+        .route("/api/drives/:id/eject", post(eject_disc))
+        .route("/api/drives/:id/close", post(close_tray))
+        .route("/api/drives/:id/lock", post(lock_tray))
+        .route("/api/drives/:id/unlock", post(unlock_tray))
         .nest_service("/static", ServeDir::new("style"))
         .with_state(app_state);
 
@@ -116,21 +210,60 @@ async fn update_system_status(app_state: AppState) {
     loop {
         interval.tick().await;
 
+        let rip_statuses = app_state.rip_statuses.read().await.clone();
+
         let mut drives = Vec::new();
         for device in &app_state.settings.options.devices {
-            let disc = Disc::new(device);
-            let disc_present = tokio::fs::File::open(device).await.is_ok();
+            let rip = rip_statuses.get(device).cloned();
+
+            let state = match monitor::drive_state(device).await {
+                Ok(state) => state,
+                Err(e) => {
+                    drives.push(DriveStatus {
+                        id: config::drive_id(device).to_owned(),
+                        device: device.clone(),
+                        state: None,
+                        disc_type: None,
+                        disc_title: None,
+                        status: format!("Error: {}", e),
+                        rip,
+                    });
+                    continue;
+                }
+            };
+            let disc_present = state == DriveState::DiscOk;
+
+            let disc = match Disc::new(device) {
+                Ok(disc) => disc,
+                Err(e) => {
+                    drives.push(DriveStatus {
+                        id: config::drive_id(device).to_owned(),
+                        device: device.clone(),
+                        state: Some(state),
+                        disc_type: None,
+                        disc_title: None,
+                        status: format!("Error: {}", e),
+                        rip,
+                    });
+                    continue;
+                }
+            };
 
             drives.push(DriveStatus {
+                id: config::drive_id(device).to_owned(),
                 device: device.clone(),
-                disc_present,
+                state: Some(state),
                 disc_type: disc.r#type,
                 disc_title: if disc_present {
                     Some(disc.title())
                 } else {
                     None
                 },
-                status: if disc_present {
+                // a failed rip's error is shown alongside the drive's live
+                // state until the next disc goes in
+                status: if let Some(rip) = rip.as_ref().filter(|rip| rip.error.is_none()) {
+                    format_rip_status(rip)
+                } else if disc_present {
                     match disc.r#type {
                         Some(DiscType::Dvd) | Some(DiscType::BluRay) => "Ready to rip".to_string(),
                         Some(DiscType::Data) => "Data disc".to_string(),
@@ -138,8 +271,9 @@ async fn update_system_status(app_state: AppState) {
                         None => "Unknown disc type".to_string(),
                     }
                 } else {
-                    "No disc".to_string()
+                    state.to_string()
                 },
+                rip,
             });
         }
 
@@ -149,22 +283,50 @@ async fn update_system_status(app_state: AppState) {
             .get_active_jobs()
             .await
             .into_iter()
-            .map(|job| HandbrakeJob {
-                id: job.id,
-                source: job.source,
-                destination: job.destination,
-                progress: job.progress,
-                status: job.status,
-                started_at: job.started_at,
-            })
+            .map(HandbrakeJob::from)
+            .collect();
+
+        let job_history = app_state
+            .handbrake_process
+            .get_job_history()
+            .await
+            .into_iter()
+            .take(RECENT_HISTORY)
+            .map(HandbrakeJob::from)
             .collect();
 
         let queue_size = app_state.handbrake_process.get_queue_size().await;
 
+        let mut reviews: Vec<_> = app_state.reviews.read().await.values().cloned().collect();
+        reviews.sort_by_key(|review| std::cmp::Reverse(review.ripped_at));
+
         let mut status = app_state.system_status.write().await;
         status.drives = drives;
         status.handbrake_jobs = handbrake_jobs;
+        status.job_history = job_history;
         status.queue_size = queue_size;
+        status.reviews = reviews;
+        status.pending_episodes = app_state.series.get_pending().await;
+        status.duplicates = app_state.catalog.get_prompts().await;
+    }
+}
+
+fn format_rip_status(rip: &RipStatus) -> String {
+    let percent = (rip.total_progress * 100.0).round();
+
+    if rip.title_index == 0 {
+        match &rip.operation {
+            Some(operation) if rip.total_progress > 0.0 => {
+                format!("{} \u{2014} {}%", operation, percent)
+            }
+            Some(operation) => operation.clone(),
+            None => "Starting rip".to_string(),
+        }
+    } else {
+        format!(
+            "Ripping title {}/{} \u{2014} {}%",
+            rip.title_index, rip.title_count, percent
+        )
     }
 }
 
@@ -173,21 +335,345 @@ async fn serve_app() -> axum::response::Html<String> {
     axum::response::Html(html.to_string())
 }
 
+async fn serve_library() -> axum::response::Html<String> {
+    let html = include_str!("../templates/library.html");
+    axum::response::Html(html.to_string())
+}
+
 async fn get_status(State(app_state): State<AppState>) -> Json<SystemStatus> {
     let status = app_state.system_status.read().await;
     Json(status.clone())
 }
 
+#[derive(Debug, Deserialize)]
+pub struct JobsQuery {
+    pub state: Option<String>,
+}
+
+/// All jobs, optionally only those in the given state (e.g. `?state=Failed`).
+async fn get_jobs(
+    Query(query): Query<JobsQuery>,
+    State(app_state): State<AppState>,
+) -> Json<Vec<HandbrakeJob>> {
+    let jobs = app_state.handbrake_process.get_all_jobs().await;
+    Json(
+        jobs.into_iter()
+            .filter(|job| {
+                query
+                    .state
+                    .as_deref()
+                    .is_none_or(|state| job.state.kind().eq_ignore_ascii_case(state))
+            })
+            .map(HandbrakeJob::from)
+            .collect(),
+    )
+}
+
+async fn get_job_history(State(app_state): State<AppState>) -> Json<Vec<HandbrakeJob>> {
+    let history = app_state.handbrake_process.get_job_history().await;
+    Json(history.into_iter().map(HandbrakeJob::from).collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PriorityRequest {
+    pub priority: i32,
+}
+
+async fn cancel_job(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = app_state.handbrake_process.cancel(&id).await;
+    job_control_response(result, format!("Cancelled job {}", id))
+}
+
+async fn pause_job(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = app_state.handbrake_process.pause(&id).await;
+    job_control_response(result, format!("Paused job {}", id))
+}
+
+async fn resume_job(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = app_state.handbrake_process.resume(&id).await;
+    job_control_response(result, format!("Resumed job {}", id))
+}
+
+async fn retry_job(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = app_state.handbrake_process.retry(&id).await;
+    job_control_response(result, format!("Requeued job {}", id))
+}
+
+async fn set_job_priority(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(request): Json<PriorityRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = app_state
+        .handbrake_process
+        .set_priority(&id, request.priority)
+        .await;
+    job_control_response(
+        result,
+        format!("Set priority of job {} to {}", id, request.priority),
+    )
+}
+
+fn job_control_response(
+    result: Result<(), JobControlError>,
+    message: String,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (status, success, message) = match result {
+        Ok(()) => (StatusCode::OK, true, message),
+        Err(e @ JobControlError::NotFound(_)) => (StatusCode::NOT_FOUND, false, e.to_string()),
+        Err(e @ JobControlError::InvalidState { .. }) => {
+            (StatusCode::CONFLICT, false, e.to_string())
+        }
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
+}
+
+/// Albums that were ripped with placeholder names and need tagging by hand.
+async fn get_reviews(State(app_state): State<AppState>) -> Json<Vec<Review>> {
+    let status = app_state.system_status.read().await;
+    Json(status.reviews.clone())
+}
+
+async fn dismiss_review(
+    Path(disc_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (status, success, message) = match music::dismiss_review(&app_state.reviews, &disc_id).await
+    {
+        Ok(true) => (
+            StatusCode::OK,
+            true,
+            format!("Dismissed review of {}", disc_id),
+        ),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            false,
+            format!("no review for disc {}", disc_id),
+        ),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, false, e.to_string()),
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
+}
+
+/// Series rips waiting on their episode mapping to be confirmed.
+async fn get_pending_episodes(State(app_state): State<AppState>) -> Json<Vec<PendingMapping>> {
+    Json(app_state.series.get_pending().await)
+}
+
+/// Confirms (and optionally adjusts) a series rip's episode mapping, then
+/// queues it for encoding.
+async fn confirm_episodes(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(request): Json<ConfirmRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let result = match app_state.series.confirm(&id, request).await {
+        Ok(folder) => app_state
+            .handbrake_process
+            .queue(
+                folder,
+                std::path::PathBuf::from(&app_state.settings.directory.output),
+            )
+            .await
+            .map_err(SeriesError::Failed),
+        Err(e) => Err(e),
+    };
+
+    let (status, success, message) = match result {
+        Ok(()) => (StatusCode::OK, true, format!("Queued {} for encoding", id)),
+        Err(e @ SeriesError::NotFound(_)) => (StatusCode::NOT_FOUND, false, e.to_string()),
+        Err(e @ SeriesError::Invalid(_)) => (StatusCode::BAD_REQUEST, false, e.to_string()),
+        Err(e @ SeriesError::Failed(_)) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, false, e.to_string())
+        }
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CatalogQuery {
+    pub q: Option<String>,
+}
+
+/// Every disc processed, newest first, optionally filtered by `?q=`.
+async fn get_catalog(
+    Query(query): Query<CatalogQuery>,
+    State(app_state): State<AppState>,
+) -> Json<Vec<CatalogEntry>> {
+    Json(
+        app_state
+            .catalog
+            .search(query.q.as_deref().unwrap_or_default())
+            .await,
+    )
+}
+
+/// Rips a disc that's been ripped before again, or skips it.
+async fn decide_duplicate(
+    Path((id, decision)): Path<(String, Decision)>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (status, success, message) = if app_state.catalog.decide(&id, decision).await {
+        (StatusCode::OK, true, format!("{:?} {}", decision, id))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            false,
+            format!("no disc waiting with id {}", id),
+        )
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
+}
+
+async fn close_tray(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    drive_action(&app_state, &id, |device| {
+        cdrom::close_tray(&device)?;
+        Ok(format!("Closed the tray of {}", device))
+    })
+    .await
+}
+
+async fn lock_tray(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let tray_locks = app_state.tray_locks.clone();
+
+    drive_action(&app_state, &id, move |device| {
+        tray_locks.set_manual(&device, true)?;
+        Ok(format!("Locked the tray of {}", device))
+    })
+    .await
+}
+
+async fn unlock_tray(
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let tray_locks = app_state.tray_locks.clone();
+
+    drive_action(&app_state, &id, move |device| {
+        Ok(if tray_locks.set_manual(&device, false)? {
+            format!("Unlocked the tray of {}", device)
+        } else {
+            format!(
+                "The tray of {} will be unlocked once its rip finishes",
+                device
+            )
+        })
+    })
+    .await
+}
+
+/// Runs a tray ioctl against a configured drive, returning its message.
+async fn drive_action(
+    app_state: &AppState,
+    id: &str,
+    action: impl FnOnce(String) -> std::io::Result<String> + Send + 'static,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (status, success, message) = match app_state.settings.options.drive(id) {
+        Some(device) => {
+            let device = device.to_owned();
+            let result = {
+                let device = device.clone();
+                tokio::task::spawn_blocking(move || action(device)).await
+            };
+
+            match result {
+                Ok(Ok(message)) => (StatusCode::OK, true, message),
+                Ok(Err(e)) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    false,
+                    format!("{}: {}", device, e),
+                ),
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, false, e.to_string()),
+            }
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            false,
+            format!("no drive with id {}", id),
+        ),
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
+}
+
+/// Ejects the disc in a configured drive.
 async fn eject_disc(
-    axum::extract::Path(device): axum::extract::Path<String>,
-    State(_app_state): State<AppState>,
-) -> Json<serde_json::Value> {
-    let device_path = format!("/dev/{}", device);
-    let disc = Disc::new(&device_path);
-    crate::disc::eject(&disc).await;
-
-    Json(serde_json::json!({
-        "success": true,
-        "message": format!("Ejected disc from {}", device_path)
-    }))
+    Path(id): Path<String>,
+    State(app_state): State<AppState>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (status, success, message) = match app_state.settings.options.drive(&id) {
+        Some(device) => match crate::disc::eject(device).await {
+            Ok(()) => (
+                StatusCode::OK,
+                true,
+                format!("Ejected disc from {}", device),
+            ),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, false, e.to_string()),
+        },
+        None => (
+            StatusCode::NOT_FOUND,
+            false,
+            format!("no drive with id {}", id),
+        ),
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "success": success,
+            "message": message
+        })),
+    )
 }